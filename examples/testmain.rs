@@ -18,15 +18,14 @@ fn main() {
             let writer = spawn(move || {
                 for i in 0..put {
                     child_map_put
-                        .as_mut()
-                        .put(format!("key {}", i), format!("value {} t {}", i, n));
+                        .insert_cloned(format!("key {}", i), format!("value {} t {}", i, n));
                 }
             });
 
             let reader = spawn(move || {
                 for i in 0..get {
                     let key = format!("key {}", i % put);
-                    child_map_get.as_mut().get(key);
+                    child_map_get.get(key.as_str(), &child_map_get.guard());
                 }
             });
             vec![writer, reader]
@@ -35,5 +34,5 @@ fn main() {
     for t in threads {
         t.join().expect("Error joining");
     }
-    print_all(&Arc::try_unwrap(shared_map).unwrap().as_mut());
+    print_all(&Arc::try_unwrap(shared_map).unwrap());
 }
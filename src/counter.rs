@@ -0,0 +1,192 @@
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicIsize, AtomicPtr, AtomicUsize, Ordering};
+
+const MIN_CELLS: usize = 4;
+// Beyond this more cells only cost memory, and summing them costs time
+const MAX_CELLS: usize = 1024;
+
+// Two cache lines: some CPUs prefetch adjacent lines in pairs
+#[repr(align(128))]
+struct Cell(AtomicIsize);
+
+// Cells that a Counter outgrew stay behind in `_next`, so their counts are never lost and
+// threads still adding to them never touch freed memory.
+struct CounterTable {
+    _cells: Box<[Cell]>,
+    _next: *mut CounterTable,
+}
+
+impl CounterTable {
+    fn new(len: usize, next: *mut CounterTable) -> CounterTable {
+        CounterTable {
+            _cells: (0..len).map(|_| Cell(AtomicIsize::new(0))).collect(),
+            _next: next,
+        }
+    }
+}
+
+/// A counter for many writers, after Cliff Click's `ConcurrentAutoTable`.
+///
+/// Updates are spread over cache line padded cells, one per thread modulo their number; the
+/// cells double whenever two threads are found updating the same one. Reading sums all cells,
+/// so it is slower than updating and, under concurrent updates, only an approximation.
+pub struct Counter {
+    _table: AtomicPtr<CounterTable>,
+}
+
+// Gives each thread its own cell while there are more cells than threads
+fn stripe() -> usize {
+    static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+    }
+    STRIPE.with(|stripe| *stripe)
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter {
+            _table: AtomicPtr::new(Box::into_raw(Box::new(CounterTable::new(
+                MIN_CELLS,
+                ptr::null_mut(),
+            )))),
+        }
+    }
+
+    /// Adds `x`, which may be negative.
+    pub fn add(&self, x: isize) {
+        let table = self._table.load(Ordering::SeqCst);
+        let cells = unsafe { &(*table)._cells };
+        let cell = &cells[stripe() & (cells.len() - 1)].0;
+        let mut old = cell.load(Ordering::SeqCst);
+        let mut contended = false;
+        while let Err(cur) =
+            cell.compare_exchange(old, old.wrapping_add(x), Ordering::SeqCst, Ordering::SeqCst)
+        {
+            old = cur;
+            contended = true;
+        }
+        if contended && cells.len() < MAX_CELLS {
+            self.grow(table);
+        }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn decrement(&self) {
+        self.add(-1);
+    }
+
+    /// Returns the sum of every update so far; exact only once concurrent updates are done.
+    pub fn get(&self) -> isize {
+        let mut sum: isize = 0;
+        let mut table = self._table.load(Ordering::SeqCst);
+        while !table.is_null() {
+            let cells = unsafe { &(*table)._cells };
+            for cell in cells.iter() {
+                sum = sum.wrapping_add(cell.0.load(Ordering::SeqCst));
+            }
+            table = unsafe { (*table)._next };
+        }
+        sum
+    }
+
+    // Replaces `table` by one with twice the cells, unless another thread already did
+    fn grow(&self, table: *mut CounterTable) {
+        let len = unsafe { &(*table)._cells }.len();
+        let newtable = Box::into_raw(Box::new(CounterTable::new(len << 1, table)));
+        if self
+            ._table
+            .compare_exchange(table, newtable, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            drop(unsafe { Box::from_raw(newtable) });
+        }
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter::new()
+    }
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Counter").field(&self.get()).finish()
+    }
+}
+
+// The cells are only ever reached through atomics
+unsafe impl Send for Counter {}
+unsafe impl Sync for Counter {}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        let mut table = *self._table.get_mut();
+        while !table.is_null() {
+            let old = unsafe { Box::from_raw(table) };
+            table = old._next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, MAX_CELLS, MIN_CELLS};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn test_counter() {
+        let counter = Counter::new();
+        assert_eq!(counter.get(), 0);
+        counter.increment();
+        counter.add(10);
+        counter.decrement();
+        counter.add(-20);
+        assert_eq!(counter.get(), -10);
+    }
+
+    #[test]
+    fn test_counter_grow_keeps_counts() {
+        let counter = Counter::new();
+        counter.add(5);
+        let table = counter._table.load(Ordering::SeqCst);
+        counter.grow(table);
+        counter.grow(table); // Already grown
+        counter.add(5);
+        assert_eq!(counter.get(), 10);
+        let cells = unsafe { &(*counter._table.load(Ordering::SeqCst))._cells }.len();
+        assert_eq!(cells, MIN_CELLS << 1);
+    }
+
+    #[test]
+    fn test_counter_concurrent() {
+        let nthreads = 16;
+        let counter = Arc::new(Counter::new());
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let counter = counter.clone();
+                spawn(move || {
+                    for _ in 0..100_000 {
+                        counter.increment();
+                        if t % 2 == 0 {
+                            counter.decrement();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert_eq!(counter.get(), nthreads / 2 * 100_000);
+        let cells = unsafe { &(*counter._table.load(Ordering::SeqCst))._cells }.len();
+        assert!(cells <= MAX_CELLS);
+    }
+}
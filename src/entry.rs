@@ -0,0 +1,145 @@
+use std::hash::{BuildHasher, Hash};
+
+use crate::keyvalue::{Key, Value};
+use crate::{Guard, MatchingTypes, NonBlockingHashMap};
+
+/// A view into a single entry of a `NonBlockingHashMap`, see `NonBlockingHashMap::entry`.
+///
+/// Other threads keep updating the map, so an entry only tells what was found when it was
+/// looked up. Every operation on it is a single conditional put that re-checks the slot.
+pub enum Entry<'g, K, V, S> {
+    Occupied(OccupiedEntry<'g, K, V, S>),
+    Vacant(VacantEntry<'g, K, V, S>),
+}
+
+/// An entry whose key was mapped to a value when it was looked up.
+pub struct OccupiedEntry<'g, K, V, S> {
+    map: &'g NonBlockingHashMap<K, V, S>,
+    guard: &'g Guard,
+    key: Key<K>,
+    value: &'g V,
+}
+
+/// An entry whose key was absent when it was looked up.
+pub struct VacantEntry<'g, K, V, S> {
+    map: &'g NonBlockingHashMap<K, V, S>,
+    guard: &'g Guard,
+    key: Key<K>,
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Entry<'g, K, V, S> {
+    pub(crate) fn new(map: &'g NonBlockingHashMap<K, V, S>, key: K, guard: &'g Guard) -> Self {
+        match map.get(&key, guard) {
+            Some(value) => Entry::Occupied(OccupiedEntry {
+                map,
+                guard,
+                key: Key::new(key),
+                value,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                map,
+                guard,
+                key: Key::new(key),
+            }),
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the value of an occupied entry, or inserts `default` if the key is still absent.
+    ///
+    /// Racing inserts into the same vacant entry have a single winner, whose value every one of
+    /// them returns.
+    pub fn or_insert(self, default: V) -> &'g V {
+        self.or_insert_with(|| default)
+    }
+
+    /// Same as `or_insert`, only calling `default` if the entry is vacant.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'g V {
+        match self {
+            Entry::Occupied(entry) => entry.get(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Same as `or_insert` with `V::default()`.
+    pub fn or_default(self) -> &'g V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Replaces the value of an occupied entry with `f` of it.
+    ///
+    /// The new value is only put if the current one is still the very value `f` was given, not
+    /// just an equal one, otherwise `f` is called again on the current one. If the key gets
+    /// removed meanwhile the entry becomes vacant.
+    pub fn and_modify<F: FnMut(&V) -> V>(self, mut f: F) -> Self {
+        let Entry::Occupied(mut entry) = self else {
+            return self;
+        };
+        loop {
+            let newval = f(entry.value);
+            let key = Box::into_raw(Box::new(entry.key.share()));
+            match unsafe {
+                entry.map.compare_and_set_impl(
+                    key,
+                    entry.value,
+                    newval,
+                    MatchingTypes::MatchPayload,
+                    entry.guard,
+                )
+            } {
+                Ok(value) => {
+                    entry.value = value;
+                    return Entry::Occupied(entry);
+                }
+                Err(Some(value)) => entry.value = value,
+                Err(None) => {
+                    return Entry::Vacant(VacantEntry {
+                        map: entry.map,
+                        guard: entry.guard,
+                        key: entry.key,
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl<'g, K, V, S> OccupiedEntry<'g, K, V, S> {
+    pub fn key(&self) -> &K {
+        self.key.get()
+    }
+
+    /// The value the key was mapped to when the entry was looked up, valid as long as the guard.
+    pub fn get(&self) -> &'g V {
+        self.value
+    }
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher>
+    VacantEntry<'g, K, V, S>
+{
+    pub fn key(&self) -> &K {
+        self.key.get()
+    }
+
+    /// Maps the key to `value` if it is still absent, like `put_if_absent`.
+    ///
+    /// Returns the value the key ends up mapped to: `value` for the winner of racing inserts,
+    /// the winner's value for everyone else.
+    pub fn insert(self, value: V) -> &'g V {
+        let key = Box::into_raw(Box::new(self.key));
+        let value = Box::into_raw(Box::new(Value::new(value)));
+        match unsafe { self.map.put_if_absent_impl(key, value, self.guard) } {
+            Ok(value) | Err(value) => value,
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::hash::{BuildHasher, Hash};
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::keyvalue::{Key, KeyTypes::KeyType, Value, ValueTypes::ValueType};
+use crate::kvtable::KVs;
+use crate::{pin, Guard, NonBlockingHashMap};
+
+/// Iterator over the entries of a `NonBlockingHashMap`, see `NonBlockingHashMap::iter`.
+pub struct Iter<'g, K, V, S> {
+    map: &'g NonBlockingHashMap<K, V, S>,
+    guard: &'g Guard,
+    // Keys never leave a table, so walking the key slots of one table sees each key once
+    kvs: *mut KVs<K, V>,
+    idx: usize,
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Iter<'g, K, V, S> {
+    pub(crate) fn new(map: &'g NonBlockingHashMap<K, V, S>, guard: &'g Guard) -> Self {
+        Iter {
+            map,
+            guard,
+            kvs: unsafe { map.snapshot(guard) },
+            idx: 0,
+        }
+    }
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Iterator
+    for Iter<'g, K, V, S>
+{
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<(&'g K, &'g V)> {
+        unsafe {
+            while self.idx < (*self.kvs).len() {
+                let idx = self.idx;
+                self.idx += 1;
+                let k = (*self.kvs).get_key_nonatomic_at(idx);
+                if Key::keytype(k) != KeyType {
+                    continue;
+                }
+                let key = (*k).get();
+                let v = (*self.kvs).get_value_nonatomic_at(idx);
+                // A prime means a later resize is copying the entry, the newest table has
+                // the current value
+                let value = if Value::is_prime(v) {
+                    self.map.get(key, self.guard)
+                } else {
+                    Value::get_value(v)
+                };
+                if let Some(value) = value {
+                    return Some((key, value));
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Iterator over the keys of a `NonBlockingHashMap`, see `NonBlockingHashMap::keys`.
+pub struct Keys<'g, K, V, S> {
+    inner: Iter<'g, K, V, S>,
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Keys<'g, K, V, S> {
+    pub(crate) fn new(map: &'g NonBlockingHashMap<K, V, S>, guard: &'g Guard) -> Self {
+        Keys {
+            inner: Iter::new(map, guard),
+        }
+    }
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Iterator
+    for Keys<'g, K, V, S>
+{
+    type Item = &'g K;
+
+    fn next(&mut self) -> Option<&'g K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+/// Iterator over the values of a `NonBlockingHashMap`, see `NonBlockingHashMap::values`.
+pub struct Values<'g, K, V, S> {
+    inner: Iter<'g, K, V, S>,
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher>
+    Values<'g, K, V, S>
+{
+    pub(crate) fn new(map: &'g NonBlockingHashMap<K, V, S>, guard: &'g Guard) -> Self {
+        Values {
+            inner: Iter::new(map, guard),
+        }
+    }
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Iterator
+    for Values<'g, K, V, S>
+{
+    type Item = &'g V;
+
+    fn next(&mut self) -> Option<&'g V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+}
+
+/// Iterator removing the entries of a `NonBlockingHashMap`, see `NonBlockingHashMap::drain`.
+///
+/// Dropping it removes the entries it has not yielded yet.
+pub struct Drain<'g, K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher,
+{
+    inner: Iter<'g, K, V, S>,
+}
+
+impl<'g, K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> Drain<'g, K, V, S> {
+    pub(crate) fn new(map: &'g NonBlockingHashMap<K, V, S>, guard: &'g Guard) -> Self {
+        Drain {
+            inner: Iter::new(map, guard),
+        }
+    }
+}
+
+impl<K, V, S> Iterator for Drain<'_, K, V, S>
+where
+    K: Eq + Hash + Send + Clone + 'static,
+    V: Eq + Send + Clone + 'static,
+    S: BuildHasher,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        let (map, guard) = (self.inner.map, self.inner.guard);
+        for (key, _) in self.inner.by_ref() {
+            // Yield whatever the key maps to when it is removed, it may have changed since
+            if let Some(value) = unsafe { map.remove_impl(key, None, guard) } {
+                return Some((key.clone(), value.clone()));
+            }
+        }
+        None
+    }
+}
+
+impl<K, V, S> Drop for Drain<'_, K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        let (map, guard) = (self.inner.map, self.inner.guard);
+        for (key, _) in self.inner.by_ref() {
+            unsafe { map.remove_impl(key, None, guard) };
+        }
+    }
+}
+
+/// Owning iterator over the entries of a `NonBlockingHashMap`, see its `IntoIterator` impl.
+pub struct IntoIter<K, V> {
+    // The only table left once the map is consumed; entries not yet taken are dropped with it
+    kvs: Box<KVs<K, V>>,
+    idx: usize,
+}
+
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static> IntoIter<K, V> {
+    pub(crate) fn new<S: BuildHasher>(map: NonBlockingHashMap<K, V, S>) -> Self {
+        let guard = &pin();
+        let kvs = unsafe { map.snapshot(guard) };
+        // Detach the table so dropping the map leaves it alone
+        map._kvs.store(ptr::null_mut(), Ordering::SeqCst);
+        IntoIter {
+            kvs: unsafe { Box::from_raw(kvs) },
+            idx: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while self.idx < self.kvs.len() {
+            let slot = &self.kvs._table[self.idx];
+            self.idx += 1;
+            let k = slot._key.load(Ordering::SeqCst);
+            let v = slot._value.load(Ordering::SeqCst);
+            if Key::keytype(k) != KeyType || Value::valuetype(v) != ValueType {
+                continue;
+            }
+            slot._key.store(Key::empty(), Ordering::SeqCst);
+            slot._value.store(Value::empty(), Ordering::SeqCst);
+            // Tables retired by a resize may still share the payloads. Nothing reads them once
+            // the map is consumed, only their Keys and Values are left to free.
+            unsafe {
+                let key = (*Box::from_raw(k)).take();
+                let value = (*Box::from_raw(Value::unprime(v))).take();
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
@@ -1,7 +1,44 @@
+use std::borrow::Borrow;
 use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// The payload of a Key or Value, shared with the ones a resize puts into the new table. A
+// consumed map takes payloads out of its last table while retired tables may still share them;
+// dropping those then only frees the allocation.
+pub struct Payload<T> {
+    inner: ManuallyDrop<T>,
+    taken: AtomicBool,
+}
+
+impl<T> Payload<T> {
+    fn new(v: T) -> Arc<Payload<T>> {
+        Arc::new(Payload {
+            inner: ManuallyDrop::new(v),
+            taken: AtomicBool::new(false),
+        })
+    }
+
+    // Nothing may read the payload through another share afterwards.
+    unsafe fn take(payload: Arc<Payload<T>>) -> T {
+        // Dropping the last share synchronizes with this store
+        payload.taken.store(true, Ordering::Relaxed);
+        ptr::read(&*payload.inner)
+    }
+}
+
+impl<T> Drop for Payload<T> {
+    fn drop(&mut self) {
+        if !*self.taken.get_mut() {
+            unsafe { ManuallyDrop::drop(&mut self.inner) };
+        }
+    }
+}
 
 // ---Key-or-Value Slot Type--------------------------------------------------------------------------------
+#[allow(clippy::enum_variant_names)]
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum KeyTypes {
     KeyType,
@@ -9,53 +46,93 @@ pub enum KeyTypes {
     KeyEmpty,
 }
 
-pub struct Key<T> {
-    // TODO: instead of having a key type field, should make Key a sum type
-    pub _keytype: KeyTypes,
-    pub _key: *mut T,
+// A key slot holds null (KeyEmpty), the KEY_TOMBSTONE sentinel, or a boxed Key, so a new table
+// is nothing but zeroed memory. Boxes are at least pointer aligned, the sentinel can never be
+// mistaken for one.
+const KEY_TOMBSTONE: usize = 1;
+
+// A Key lives in at most one table slot. A resize puts the same key into the new table
+// through a second Key sharing the payload, so whichever table goes last frees it.
+pub enum Key<T> {
+    Owned(Arc<Payload<T>>),
+    // Only used for probing, never installed in a table
+    Borrowed(*const T),
 }
 
-impl<T: Hash> Key<T> {
+impl<T> Key<T> {
     pub fn new(k: T) -> Key<T> {
-        Key {
-            _keytype: KeyTypes::KeyType,
-            _key: Box::into_raw(Box::new(k)),
+        Key::Owned(Payload::new(k))
+    }
+
+    // Another Key sharing this key's payload. Every put consumes the Key it is given, into a
+    // slot or freed, so an operation that may retry keeps one Key and puts a share each time.
+    pub fn share(&self) -> Key<T> {
+        match self {
+            Key::Owned(k) => Key::Owned(k.clone()),
+            Key::Borrowed(_) => panic!("a borrowed key is never installed in a table"),
         }
     }
 
-    //pub fn new_pointer(k: *T) -> Key<T> {
-    //Key { _keytype: KeyType, _key: k }
-    //}
+    // The payload, even if other Keys still share it; those must never be read again.
+    pub unsafe fn take(self) -> T {
+        match self {
+            Key::Owned(k) => Payload::take(k),
+            Key::Borrowed(_) => panic!("a borrowed key is never installed in a table"),
+        }
+    }
+
+    // A key that borrows `k` for probing; `k` has to outlive it.
+    pub unsafe fn new_borrowed(k: &T) -> Key<T> {
+        Key::Borrowed(k)
+    }
 
-    pub fn new_empty() -> Key<T> {
-        Key {
-            _keytype: KeyTypes::KeyEmpty,
-            _key: ptr::null_mut(),
+    pub fn get(&self) -> &T {
+        match self {
+            Key::Owned(k) => &k.inner,
+            Key::Borrowed(k) => unsafe { &**k },
         }
     }
 
-    pub fn new_tombstone() -> Key<T> {
-        Key {
-            _keytype: KeyTypes::KeyTombStone,
-            _key: ptr::null_mut(),
+    pub fn empty() -> *mut Key<T> {
+        ptr::null_mut()
+    }
+
+    pub fn tombstone() -> *mut Key<T> {
+        ptr::without_provenance_mut(KEY_TOMBSTONE)
+    }
+
+    pub fn keytype(k: *mut Key<T>) -> KeyTypes {
+        if k.is_null() {
+            KeyTypes::KeyEmpty
+        } else if k.addr() == KEY_TOMBSTONE {
+            KeyTypes::KeyTombStone
+        } else {
+            KeyTypes::KeyType
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self._keytype == KeyTypes::KeyEmpty
+    pub fn is_empty(k: *mut Key<T>) -> bool {
+        k.is_null()
     }
 
-    pub fn is_tombstone(&self) -> bool {
-        self._keytype == KeyTypes::KeyTombStone
+    pub fn is_tombstone(k: *mut Key<T>) -> bool {
+        k.addr() == KEY_TOMBSTONE
     }
 
-    pub fn keytype(&self) -> KeyTypes {
-        self._keytype
+    // Frees whatever a key slot pointed to; sentinels own nothing.
+    pub unsafe fn free(k: *mut Key<T>) {
+        if Key::keytype(k) == KeyTypes::KeyType {
+            drop(Box::from_raw(k));
+        }
     }
 
-    pub fn get_key(&self) -> *mut T {
-        assert!(!self._key.is_null());
-        self._key
+    // Compares against a borrowed form of the key without building a Key<T> for it.
+    pub fn matches<Q>(&self, key: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.get().borrow() == key
     }
 
     // ---Hash Function--------------------------------------------------------------------------------------
@@ -74,164 +151,185 @@ impl<T: Hash> Key<T> {
 
 impl<T: Hash> Hash for Key<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        unsafe { (*self._key).hash(state) };
+        self.get().hash(state);
     }
 }
 
-impl<T> Drop for Key<T> {
-    fn drop(&mut self) {
-        if self._keytype == KeyTypes::KeyType {
-            drop(unsafe { Box::from_raw(self._key) });
-        }
-    }
-}
-
-impl<T: PartialEq + Hash> PartialEq for Key<T> {
+impl<T: PartialEq> PartialEq for Key<T> {
     fn eq(&self, other: &Key<T>) -> bool {
-        if self._keytype != other._keytype {
-            return false;
-        }
-        if self._keytype == KeyTypes::KeyEmpty && other._keytype == KeyTypes::KeyEmpty {
-            return true;
-        }
-        if self._keytype == KeyTypes::KeyTombStone && other._keytype == KeyTypes::KeyTombStone {
-            return true;
-        }
-        assert!(!self._key.is_null() && !other._key.is_null());
-        self._key == other._key || unsafe { *self._key == *other._key }
+        ptr::eq(self.get(), other.get()) || self.get() == other.get()
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum ValueTypes {
     ValueType,
     ValueTombStone,
     ValueEmpty,
+    ValuePending,
 }
 
-pub struct Value<T> {
-    // TODO: instead of having a key type field, should make Value a sum type
-    pub _valuetype: ValueTypes,
-    pub _value: *mut T,
-    pub _is_prime: bool,
+// A value slot holds null (ValueEmpty), the VALUE_TOMBSTONE sentinel, the VALUE_PENDING
+// sentinel of a value still being computed, or a boxed Value. The lowest bit marks a prime, so
+// priming a value during a resize is a CAS on the same box.
+const VALUE_PRIME: usize = 1;
+const VALUE_TOMBSTONE: usize = 2;
+const VALUE_PENDING: usize = 4;
+
+// Like Key, a Value lives in at most one table slot and shares its payload with the Value a
+// resize puts into the new table. Priming only tags the slot pointer, the box stays the same.
+pub enum Value<T> {
+    Owned(Arc<Payload<T>>),
+    // Only used as an expected value, never installed in a table
+    Borrowed(*const T),
 }
 
 impl<T> Value<T> {
     pub fn new(v: T) -> Value<T> {
-        Value {
-            _valuetype: ValueTypes::ValueType,
-            _value: Box::into_raw(Box::new(v)),
-            _is_prime: false,
-        }
+        Value::Owned(Payload::new(v))
     }
 
-    pub fn new_empty() -> Value<T> {
-        Value {
-            _valuetype: ValueTypes::ValueEmpty,
-            _value: ptr::null_mut(),
-            _is_prime: false,
+    // Another Value sharing this value's payload, see Key::share.
+    pub fn share(&self) -> Value<T> {
+        match self {
+            Value::Owned(v) => Value::Owned(v.clone()),
+            Value::Borrowed(_) => panic!("a borrowed value is never installed in a table"),
         }
     }
 
-    pub fn new_tombstone() -> Value<T> {
-        Value {
-            _valuetype: ValueTypes::ValueTombStone,
-            _value: ptr::null_mut(),
-            _is_prime: false,
+    // The payload, even if other Values still share it; those must never be read again.
+    pub unsafe fn take(self) -> T {
+        match self {
+            Value::Owned(v) => Payload::take(v),
+            Value::Borrowed(_) => panic!("a borrowed value is never installed in a table"),
         }
     }
 
-    pub fn new_tombprime() -> Value<T> {
-        Value {
-            _valuetype: ValueTypes::ValueTombStone,
-            _value: ptr::null_mut(),
-            _is_prime: true,
+    // A value that borrows `v` to be compared against; `v` has to outlive it.
+    pub unsafe fn new_borrowed(v: &T) -> Value<T> {
+        Value::Borrowed(v)
+    }
+
+    pub fn get(&self) -> &T {
+        match self {
+            Value::Owned(v) => &v.inner,
+            Value::Borrowed(v) => unsafe { &**v },
         }
     }
 
-    pub fn new_prime(v: T) -> Value<T> {
-        Value {
-            _valuetype: ValueTypes::ValueType,
-            _value: Box::into_raw(Box::new(v)),
-            _is_prime: true,
+    pub fn empty() -> *mut Value<T> {
+        ptr::null_mut()
+    }
+
+    pub fn tombstone() -> *mut Value<T> {
+        ptr::without_provenance_mut(VALUE_TOMBSTONE)
+    }
+
+    pub fn tombprime() -> *mut Value<T> {
+        Value::prime(Value::tombstone())
+    }
+
+    // Stands in for a value one thread is computing while others wait for it. Everything but
+    // those waiters treats the key as absent.
+    pub fn pending() -> *mut Value<T> {
+        ptr::without_provenance_mut(VALUE_PENDING)
+    }
+
+    pub fn valuetype(v: *mut Value<T>) -> ValueTypes {
+        let v = Value::unprime(v);
+        if v.is_null() {
+            ValueTypes::ValueEmpty
+        } else if v.addr() == VALUE_TOMBSTONE {
+            ValueTypes::ValueTombStone
+        } else if v.addr() == VALUE_PENDING {
+            ValueTypes::ValuePending
+        } else {
+            ValueTypes::ValueType
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        assert!(self._value.is_null() == (self._valuetype == ValueTypes::ValueEmpty));
-        self._valuetype == ValueTypes::ValueEmpty
+    pub fn is_empty(v: *mut Value<T>) -> bool {
+        v.is_null()
     }
 
-    pub fn is_tombstone(&self) -> bool {
-        self._valuetype == ValueTypes::ValueTombStone
+    pub fn is_tombstone(v: *mut Value<T>) -> bool {
+        Value::valuetype(v) == ValueTypes::ValueTombStone
     }
 
-    pub fn is_prime(&self) -> bool {
-        self._is_prime
+    pub fn is_pending(v: *mut Value<T>) -> bool {
+        Value::valuetype(v) == ValueTypes::ValuePending
     }
 
-    pub fn is_tombprime(&self) -> bool {
-        self.is_prime() && self.is_tombstone()
+    // No live value: empty, tombstone or pending.
+    pub fn is_absent(v: *mut Value<T>) -> bool {
+        Value::valuetype(v) != ValueTypes::ValueType
     }
 
-    pub fn get_prime(&self) -> *mut Value<T> {
-        assert!(!self.is_prime());
-        Box::into_raw(Box::new(Value {
-            _valuetype: self._valuetype,
-            _value: self._value,
-            _is_prime: true,
-        }))
+    pub fn is_prime(v: *mut Value<T>) -> bool {
+        v.addr() & VALUE_PRIME != 0
     }
 
-    pub fn get_unprime(&self) -> *mut Value<T> {
-        assert!(self.is_prime());
-        Box::into_raw(Box::new(Value {
-            _valuetype: self._valuetype,
-            _value: self._value,
-            _is_prime: false,
-        }))
+    pub fn is_tombprime(v: *mut Value<T>) -> bool {
+        Value::is_prime(v) && Value::is_tombstone(v)
     }
 
-    pub fn valuetype(&self) -> ValueTypes {
-        self._valuetype
+    pub fn prime(v: *mut Value<T>) -> *mut Value<T> {
+        assert!(!Value::is_prime(v));
+        v.map_addr(|a| a | VALUE_PRIME)
     }
 
-    pub fn get_value(&self) -> *mut T {
-        self._value
+    pub fn unprime(v: *mut Value<T>) -> *mut Value<T> {
+        v.map_addr(|a| a & !VALUE_PRIME)
     }
-}
 
-impl<T> Drop for Value<T> {
-    fn drop(&mut self) {
-        if self._valuetype == ValueTypes::ValueType {
-            drop(unsafe { Box::from_raw(self._value) });
+    // The payload of a live value slot, primed or not.
+    pub unsafe fn get_value<'a>(v: *mut Value<T>) -> Option<&'a T> {
+        if Value::valuetype(v) == ValueTypes::ValueType {
+            Some((*Value::unprime(v)).get())
+        } else {
+            None
         }
     }
-}
 
-impl<T: PartialEq> PartialEq for Value<T> {
-    fn eq(&self, other: &Value<T>) -> bool {
-        if self._valuetype != other._valuetype {
+    // Compares two value slots; live values are compared by payload.
+    pub unsafe fn same(a: *mut Value<T>, b: *mut Value<T>) -> bool
+    where
+        T: PartialEq,
+    {
+        if a == b {
+            return true;
+        }
+        if Value::is_prime(a) != Value::is_prime(b) {
             return false;
         }
-        if self._valuetype == ValueTypes::ValueEmpty && other._valuetype == ValueTypes::ValueEmpty {
-            return true;
+        match (Value::get_value(a), Value::get_value(b)) {
+            (Some(x), Some(y)) => x == y,
+            _ => false,
         }
-        if self._valuetype == ValueTypes::ValueTombStone
-            && other._valuetype == ValueTypes::ValueTombStone
-            && self._is_prime == other._is_prime
-        {
-            return true;
+    }
+
+    // Whether two live value slots hold the same payload. A resize shares the payload of the
+    // value it copies, so the new slot still holds the same one.
+    pub unsafe fn same_payload(a: *mut Value<T>, b: *mut Value<T>) -> bool {
+        match (Value::get_value(a), Value::get_value(b)) {
+            (Some(x), Some(y)) => ptr::eq(x, y),
+            _ => false,
+        }
+    }
+
+    // Frees whatever a value slot pointed to; sentinels own nothing.
+    pub unsafe fn free(v: *mut Value<T>) {
+        if Value::valuetype(v) == ValueTypes::ValueType {
+            drop(Box::from_raw(Value::unprime(v)));
         }
-        assert!(!self._value.is_null() && !other._value.is_null());
-        (self._value == other._value || unsafe { *self._value == *other._value })
-            && self._is_prime == other._is_prime
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{Key, Value};
+    use std::rc::Rc;
 
     #[test]
     fn test_key_drop() {
@@ -241,27 +339,78 @@ mod tests {
 
     #[test]
     fn test_key_empty_tombstone_drop() {
-        drop(Key::<String>::new_empty());
-        drop(Key::<String>::new_tombstone());
+        unsafe {
+            Key::<String>::free(Key::empty());
+            Key::<String>::free(Key::tombstone());
+        }
     }
 
     #[test]
     fn test_value_drop() {
         drop(Value::new(42));
-        drop(Value::new_prime(42));
         drop(Value::new(String::from("Hello")));
-        drop(Value::new_prime(String::from("Hello")));
-        // FIXME: double free with get_prime()
-        // drop(unsafe { Box::from_raw(Value::new(String::from("Hello")).get_prime()) });
-        // drop(unsafe { Box::from_raw(Value::new_prime(String::from("Hello")).get_unprime()) });
+        let v = Box::into_raw(Box::new(Value::new(String::from("Hello"))));
+        unsafe { Value::free(Value::prime(v)) };
+    }
+
+    #[test]
+    fn test_value_prime_share_drop() {
+        // A resize primes the old slot and puts a second Value into the new table; freeing
+        // both must drop the payload exactly once.
+        let payload = Rc::new(());
+        unsafe {
+            let v = Box::into_raw(Box::new(Value::new(payload.clone())));
+            let primed = Value::prime(v);
+            let copy = Box::into_raw(Box::new((*Value::unprime(primed)).share()));
+            assert_eq!(Rc::strong_count(&payload), 2);
+            Value::free(primed);
+            assert_eq!(Rc::strong_count(&payload), 2);
+            Value::free(Value::unprime(Value::prime(copy)));
+        }
+        assert_eq!(Rc::strong_count(&payload), 1);
+    }
+
+    #[test]
+    fn test_key_share_drop() {
+        let payload = Rc::new(());
+        let key = Key::new(payload.clone());
+        let copy = key.share();
+        drop(key);
+        assert_eq!(Rc::strong_count(&payload), 2);
+        drop(copy);
+        assert_eq!(Rc::strong_count(&payload), 1);
+    }
+
+    #[test]
+    fn test_take_shared() {
+        let payload = Rc::new(());
+        let key = Key::new(payload.clone());
+        let copy = key.share();
+        let taken = unsafe { key.take() };
+        assert!(Rc::ptr_eq(&taken, &payload));
+        // Dropping the other Key leaves the payload alone
+        drop(copy);
+        assert_eq!(Rc::strong_count(&payload), 2);
+        drop(taken);
+        let value = Value::new(payload.clone());
+        let copy = value.share();
+        drop(copy);
+        let taken = unsafe { value.take() };
+        assert!(Rc::ptr_eq(&taken, &payload));
+        drop(taken);
+        assert_eq!(Rc::strong_count(&payload), 1);
     }
 
     #[test]
     fn test_value_tombstones_drop() {
-        drop(Value::<String>::new_tombstone());
-        // FIXME: double free with get_prime()
-        // drop(unsafe { Box::from_raw(Value::<String>::new_tombstone().get_prime()) });
-        drop(Value::<String>::new_tombprime());
-        // drop(unsafe { Box::from_raw(Value::<String>::new_tombprime().get_unprime()) });
+        unsafe {
+            Value::<String>::free(Value::empty());
+            Value::<String>::free(Value::tombstone());
+            Value::<String>::free(Value::tombprime());
+            Value::<String>::free(Value::prime(Value::tombstone()));
+            Value::<String>::free(Value::unprime(Value::tombprime()));
+            Value::<String>::free(Value::pending());
+            Value::<String>::free(Value::prime(Value::pending()));
+        }
     }
 }
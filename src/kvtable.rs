@@ -1,95 +1,115 @@
+use super::counter::Counter;
 use super::keyvalue::{Key, Value};
 use std::hash::Hash;
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 
 pub static REPROBE_LIMIT: usize = 10;
 
 // ---Hash Table Layer Node -------------------------------------------------------------------------------
+// All three fields are valid when zeroed: an empty key, an empty value and an unpublished hash.
+pub struct Slot<K, V> {
+    pub _key: AtomicPtr<Key<K>>,
+    pub _value: AtomicPtr<Value<V>>,
+    pub _hash: AtomicU64, // Full hash of the key, 0 until published
+}
+
 pub struct KVs<K, V> {
-    pub _ks: Vec<AtomicPtr<Key<K>>>,
-    pub _vs: Vec<AtomicPtr<Value<V>>>,
+    pub _table: Box<[Slot<K, V>]>,
     pub _chm: CHM<K, V>,
-    pub _hashes: Vec<u64>,
 }
 
 impl<K: Hash, V> KVs<K, V> {
     pub fn new(table_size: usize) -> KVs<K, V> {
+        KVs::new_with_size_counter(table_size, Arc::new(Counter::new()))
+    }
+
+    // A table created by a resize shares the size counter of the table it replaces.
+    pub fn new_with_size_counter(table_size: usize, size: Arc<Counter>) -> KVs<K, V> {
         KVs {
-            _ks: {
-                let mut temp = Vec::with_capacity(table_size);
-                for _ in 0..table_size {
-                    temp.push(AtomicPtr::new(Box::into_raw(Box::new(
-                        Key::<K>::new_empty(),
-                    ))));
-                }
-                temp
-            },
-            _vs: {
-                let mut temp = Vec::with_capacity(table_size);
-                for _ in 0..table_size {
-                    temp.push(AtomicPtr::new(Box::into_raw(Box::new(
-                        Value::<V>::new_empty(),
-                    ))));
-                }
-                temp
-            },
-            _chm: CHM::<K, V>::new(),
-            _hashes: vec![0; table_size],
+            _table: unsafe { Box::new_zeroed_slice(table_size).assume_init() },
+            _chm: CHM::<K, V>::new(size),
         }
     }
 
     pub fn get_key_nonatomic_at(&self, idx: usize) -> *mut Key<K> {
-        self._ks[idx].load(Ordering::SeqCst)
+        self._table[idx]._key.load(Ordering::SeqCst)
     }
 
     pub fn get_value_nonatomic_at(&self, idx: usize) -> *mut Value<V> {
-        self._vs[idx].load(Ordering::SeqCst)
+        self._table[idx]._value.load(Ordering::SeqCst)
+    }
+
+    pub fn cas_key(&self, idx: usize, old: *mut Key<K>, new: *mut Key<K>) -> bool {
+        self._table[idx]
+            ._key
+            .compare_exchange(old, new, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    pub fn cas_value(&self, idx: usize, old: *mut Value<V>, new: *mut Value<V>) -> bool {
+        self._table[idx]
+            ._value
+            .compare_exchange(old, new, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    pub fn get_hash(&self, idx: usize) -> u64 {
+        self._table[idx]._hash.load(Ordering::SeqCst)
+    }
+
+    pub fn set_hash(&self, idx: usize, fullhash: u64) {
+        self._table[idx]._hash.store(fullhash, Ordering::SeqCst)
     }
 
     pub fn table_full(&self, reprobe_cnt: usize) -> bool {
-        reprobe_cnt >= REPROBE_LIMIT && self._chm._slots.load(Ordering::SeqCst) >= self._ks.len()
+        reprobe_cnt >= REPROBE_LIMIT && self._chm._slots.get() >= self._table.len() as isize
     }
 
+    // Grows with the table: a resize keeps the size only while fewer than len/4 keys are live,
+    // and those cannot form a cluster long enough to hit the limit again.
     pub fn reprobe_limit(&self) -> usize {
-        REPROBE_LIMIT + (self._ks.len() << 2)
+        REPROBE_LIMIT + (self._table.len() >> 2)
     }
 
     pub fn len(&self) -> usize {
-        self._ks.len()
+        self._table.len()
     }
 }
 
 impl<K, V> Drop for KVs<K, V> {
     fn drop(&mut self) {
-        for i in 0..self._ks.len() {
-            drop(unsafe { Box::from_raw(self._ks[i].load(Ordering::SeqCst)) });
-            drop(unsafe { Box::from_raw(self._vs[i].load(Ordering::SeqCst)) });
+        for slot in self._table.iter() {
+            unsafe {
+                Key::free(slot._key.load(Ordering::SeqCst));
+                Value::free(slot._value.load(Ordering::SeqCst));
+            }
         }
     }
 }
 
 // ---Structure for resizing -------------------------------------------------------
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct CHM<K, V> {
+    // Not owned: once the copy is done this table is retired while _newkvs lives on.
     pub _newkvs: AtomicPtr<KVs<K, V>>,
-    pub _size: AtomicUsize,
-    pub _slots: AtomicUsize,
+    pub _size: Arc<Counter>, // Live values, shared by every table in the chain
+    pub _slots: Counter,
     pub _copy_done: AtomicUsize,
     pub _copy_idx: AtomicUsize,
-    pub _has_newkvs: bool,
     //_resizer: AtomicU32,
 }
 
 impl<K, V> CHM<K, V> {
-    pub fn new() -> CHM<K, V> {
+    pub fn new(size: Arc<Counter>) -> CHM<K, V> {
         CHM {
             _newkvs: AtomicPtr::new(ptr::null_mut()),
-            _size: AtomicUsize::new(0),
-            _slots: AtomicUsize::new(0),
+            _size: size,
+            _slots: Counter::new(),
             _copy_done: AtomicUsize::new(0),
             _copy_idx: AtomicUsize::new(0),
-            _has_newkvs: false,
         }
     }
 
@@ -98,16 +118,6 @@ impl<K, V> CHM<K, V> {
     }
 
     pub fn has_newkvs(&self) -> bool {
-        assert!((!self._newkvs.load(Ordering::SeqCst).is_null()) == self._has_newkvs);
-        self._has_newkvs
-    }
-}
-
-impl<K, V> Drop for CHM<K, V> {
-    fn drop(&mut self) {
-        let p = self._newkvs.load(Ordering::SeqCst);
-        if !p.is_null() {
-            drop(unsafe { Box::from_raw(p) });
-        }
+        !self._newkvs.load(Ordering::SeqCst).is_null()
     }
 }
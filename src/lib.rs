@@ -1,103 +1,303 @@
-use std::cell::UnsafeCell;
+use std::borrow::Borrow;
 use std::cmp::min;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-// use std::ptr;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::ptr;
 use std::string::ToString;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
 use std::time::{Duration, Instant};
 
+use crossbeam_epoch::{self as epoch, Shared};
+
+mod counter;
+mod entry;
+mod iter;
 mod keyvalue;
 mod kvtable;
 
+pub use crate::counter::Counter;
+pub use crate::entry::{Entry, OccupiedEntry, VacantEntry};
+pub use crate::iter::{Drain, IntoIter, Iter, Keys, Values};
+
 use crate::keyvalue::{
     Key, KeyTypes::KeyEmpty, KeyTypes::KeyTombStone, KeyTypes::KeyType, Value,
-    ValueTypes::ValueEmpty, ValueTypes::ValueTombStone, ValueTypes::ValueType,
+    ValueTypes::ValueEmpty, ValueTypes::ValuePending, ValueTypes::ValueTombStone,
+    ValueTypes::ValueType,
 };
-use crate::kvtable::{KVs, REPROBE_LIMIT};
+use crate::kvtable::KVs;
 
 const MIN_SIZE_LOG: u32 = 3;
 const MIN_SIZE: usize = 1 << MIN_SIZE_LOG;
 
 const MEMORY_ORDERING: Ordering = Ordering::SeqCst;
 
+/// A pinned epoch of the collector the map defers freeing memory to.
+///
+/// References lent out by the map live as long as the guard they were obtained under. Guards
+/// only come from `pin` and `NonBlockingHashMap::guard`, so memory a writer retires always
+/// waits for every guard passed to the map.
+pub struct Guard {
+    inner: epoch::Guard,
+}
+
+impl Guard {
+    /// Moves what this thread retired to the global queue and frees what can be freed.
+    pub fn flush(&self) {
+        self.inner.flush();
+    }
+
+    // Unpins for the duration of f, unless the thread holds another guard.
+    fn repin_after<F: FnOnce() -> R, R>(&mut self, f: F) -> R {
+        self.inner.repin_after(f)
+    }
+}
+
+/// Pins the current thread, see `NonBlockingHashMap::guard`.
+pub fn pin() -> Guard {
+    Guard {
+        inner: epoch::pin(),
+    }
+}
+
+// The size of a table holding `capacity` keys without a resize, up to a point.
+fn table_size_for(capacity: usize) -> usize {
+    let capacity = min(capacity, 1024 * 1024);
+    let mut i = MIN_SIZE_LOG;
+    while 1 << i < capacity << 2 {
+        i += 1;
+    }
+    1 << i
+}
+
+// Frees p once every thread that could have loaded it from a table has unpinned.
+unsafe fn retire<T>(guard: &Guard, p: *mut T) {
+    guard.inner.defer_destroy(Shared::from(p as *const T));
+}
+
+// Same for a value taken out of a slot; sentinels own nothing.
+unsafe fn retire_value<V>(guard: &Guard, v: *mut Value<V>) {
+    if Value::valuetype(v) == ValueType {
+        retire(guard, Value::unprime(v));
+    }
+}
+
+// Frees kvs and every table a resize chained onto it.
+unsafe fn free_chain<K, V>(mut kvs: *mut KVs<K, V>) {
+    while !kvs.is_null() {
+        let table = Box::from_raw(kvs);
+        kvs = table._chm.get_newkvs_nonatomic();
+    }
+}
+
+// Same as retire for a table no longer reachable from the map, along with its chain. The chain
+// is only walked once every thread that could still resize it has unpinned.
+unsafe fn retire_chain<K, V>(guard: &Guard, kvs: *mut KVs<K, V>) {
+    guard.inner.defer_unchecked(move || free_chain(kvs));
+}
+
 #[derive(PartialEq)]
 pub enum MatchingTypes {
     MatchAll,
     MatchAllNotEmpty,
     MatchValue,
+    // Like MatchValue, but only the very payload expected matches, not just an equal one
+    MatchPayload,
     FromCopySlot,
 }
 
 #[derive(Debug)]
-pub struct ConcurrentMap<K, V> {
-    inner: UnsafeCell<NonBlockingHashMap<K, V>>,
+pub struct ConcurrentMap<K, V, S = RandomState> {
+    inner: NonBlockingHashMap<K, V, S>,
 }
 
-unsafe impl<K, V> Sync for ConcurrentMap<K, V> {}
-
-impl<K: Eq + Hash, V: Eq> Default for ConcurrentMap<K, V> {
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher + Default> Default
+    for ConcurrentMap<K, V, S>
+{
     fn default() -> Self {
-        Self::new()
+        Self::with_hasher(S::default())
     }
 }
 
-impl<K: Eq + Hash, V: Eq> ConcurrentMap<K, V> {
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static> ConcurrentMap<K, V> {
     pub fn new() -> ConcurrentMap<K, V> {
         ConcurrentMap {
-            inner: UnsafeCell::new(NonBlockingHashMap::new()),
+            inner: NonBlockingHashMap::new(),
         }
     }
 
     pub fn new_with_size(initial_sz: usize) -> ConcurrentMap<K, V> {
         ConcurrentMap {
-            inner: UnsafeCell::new(NonBlockingHashMap::new_with_size(initial_sz)),
+            inner: NonBlockingHashMap::new_with_size(initial_sz),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> ConcurrentMap<K, V, S> {
+    pub fn with_hasher(hash_builder: S) -> ConcurrentMap<K, V, S> {
+        ConcurrentMap {
+            inner: NonBlockingHashMap::with_hasher(hash_builder),
+        }
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> ConcurrentMap<K, V, S> {
+        ConcurrentMap {
+            inner: NonBlockingHashMap::with_capacity_and_hasher(capacity, hash_builder),
         }
     }
+}
 
-    // "impl DerefMut for ConcurrentMap" won't work because of "deref(&mut self)"
-    #[allow(clippy::mut_from_ref)]
-    pub fn as_mut(&self) -> &mut NonBlockingHashMap<K, V> {
-        unsafe { &mut *self.inner.get() }
+// Every operation of NonBlockingHashMap works on &self, so sharing the map only needs Deref.
+impl<K, V, S> Deref for ConcurrentMap<K, V, S> {
+    type Target = NonBlockingHashMap<K, V, S>;
+
+    fn deref(&self) -> &NonBlockingHashMap<K, V, S> {
+        &self.inner
     }
 }
 
 // ---Hash Map --------------------------------------------------------------------
 #[derive(Debug)]
-pub struct NonBlockingHashMap<K, V> {
+pub struct NonBlockingHashMap<K, V, S = RandomState> {
     _kvs: AtomicPtr<KVs<K, V>>,
+    _hasher: S,
     //_reprobes: AtomicUint,
-    _last_resize: Instant,
+    _created: Instant,
+    _last_resize: AtomicU64, // Milliseconds since _created
+    _waiters: Waiters,
+    _marker: PhantomData<(K, V)>,
 }
 
-impl<K: Eq + Hash, V: Eq> Default for NonBlockingHashMap<K, V> {
+// All shared state lives behind atomics; keys and values may be dropped by whichever thread
+// replaces them, so both have to be Send as well as Sync.
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send> Send for NonBlockingHashMap<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for NonBlockingHashMap<K, V, S> {}
+
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher + Default> Default
+    for NonBlockingHashMap<K, V, S>
+{
     fn default() -> Self {
-        Self::new()
+        Self::with_hasher(S::default())
+    }
+}
+
+// Only the live end of the resize chain is reachable from _kvs, every older table has been
+// retired by copy_check_and_promote already.
+impl<K, V, S> Drop for NonBlockingHashMap<K, V, S> {
+    fn drop(&mut self) {
+        unsafe { free_chain(self._kvs.load(MEMORY_ORDERING)) };
+    }
+}
+
+/// Consumes the map, yielding every entry with ownership of its key and value.
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher> IntoIterator
+    for NonBlockingHashMap<K, V, S>
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> IntoIter<K, V> {
+        IntoIter::new(self)
+    }
+}
+
+/// Builds a map presized for the iterator's lower size bound.
+impl<K, V, S> FromIterator<(K, V)> for NonBlockingHashMap<K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let map = NonBlockingHashMap::with_capacity_and_hasher(iter.size_hint().0, S::default());
+        for (key, value) in iter {
+            map.insert(key, value, &pin());
+        }
+        map
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for NonBlockingHashMap<K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for (key, value) in iter {
+            self.insert(key, value, &pin());
+        }
+    }
+}
+
+impl<K, V, S> From<HashMap<K, V, S>> for NonBlockingHashMap<K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher + Default,
+{
+    fn from(map: HashMap<K, V, S>) -> Self {
+        map.into_iter().collect()
     }
 }
 
-impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
+// Replaced keys, values and tables are freed later, possibly by another thread, hence
+// Send + 'static.
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static> NonBlockingHashMap<K, V> {
     pub fn new() -> NonBlockingHashMap<K, V> {
         NonBlockingHashMap::new_with_size(MIN_SIZE)
     }
 
     pub fn new_with_size(initial_sz: usize) -> NonBlockingHashMap<K, V> {
-        let mut initial_sz = initial_sz;
-        if initial_sz > 1024 * 1024 {
-            initial_sz = 1024 * 1024;
-        }
-        let mut i = MIN_SIZE_LOG;
-        while 1 << i < initial_sz << 2 {
-            i += 1;
-        }
+        NonBlockingHashMap::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash + Send + 'static, V: Eq + Send + 'static, S: BuildHasher>
+    NonBlockingHashMap<K, V, S>
+{
+    /// Creates an empty map which will use `hash_builder` to hash keys.
+    pub fn with_hasher(hash_builder: S) -> NonBlockingHashMap<K, V, S> {
+        NonBlockingHashMap::with_capacity_and_hasher(MIN_SIZE, hash_builder)
+    }
 
+    /// Creates a map sized for `initial_sz` entries which will use `hash_builder` to hash keys.
+    pub fn with_capacity_and_hasher(
+        initial_sz: usize,
+        hash_builder: S,
+    ) -> NonBlockingHashMap<K, V, S> {
         NonBlockingHashMap {
-            _kvs: AtomicPtr::new(Box::into_raw(Box::new(KVs::<K, V>::new(1 << i)))),
+            _kvs: AtomicPtr::new(Box::into_raw(Box::new(KVs::<K, V>::new(table_size_for(
+                initial_sz,
+            ))))),
+            _hasher: hash_builder,
             //_reprobes: AtomicUint::new(0),
-            _last_resize: Instant::now(),
+            _created: Instant::now(),
+            _last_resize: AtomicU64::new(0),
+            _waiters: Waiters::new(),
+            _marker: PhantomData,
         }
     }
 
+    /// Pins the current thread. References handed out by the map under the returned guard stay
+    /// valid until it is dropped; keep guards short-lived, as nothing retired meanwhile is freed.
+    pub fn guard(&self) -> Guard {
+        pin()
+    }
+
+    /// Same as `guard`.
+    pub fn pin(&self) -> Guard {
+        self.guard()
+    }
+
     pub fn get_table_nonatomic(&self) -> *mut KVs<K, V> {
         self._kvs.load(MEMORY_ORDERING)
     }
@@ -109,7 +309,7 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         }
 
         let oldlen: usize = (*kvs).len();
-        let sz = (*kvs)._chm._size.load(MEMORY_ORDERING);
+        let sz = (*kvs)._chm._size.get().max(0) as usize;
         let mut newsz = sz;
 
         if sz >= oldlen >> 2 {
@@ -119,10 +319,9 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             }
         }
 
-        let tm = Instant::now();
         if newsz <= oldlen
-            && tm.duration_since(self._last_resize) <= Duration::new(1, 0)
-            && (*kvs)._chm._slots.load(MEMORY_ORDERING) >= sz << 1
+            && self.since_last_resize() <= Duration::new(1, 0)
+            && (*kvs)._chm._slots.get() >= (sz << 1) as isize
         {
             newsz = oldlen << 1;
         }
@@ -140,9 +339,13 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             return (*kvs)._chm._newkvs.load(MEMORY_ORDERING);
         }
 
-        let mut newkvs = Box::into_raw(Box::new(KVs::<K, V>::new(1 << log2)));
+        let mut newkvs = Box::into_raw(Box::new(KVs::<K, V>::new_with_size_counter(
+            1 << log2,
+            (*kvs)._chm._size.clone(),
+        )));
 
         if (*kvs)._chm.has_newkvs() {
+            drop(Box::from_raw(newkvs));
             return (*kvs)._chm._newkvs.load(MEMORY_ORDERING);
         }
 
@@ -150,72 +353,501 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         if (*kvs)
             ._chm
             ._newkvs
-            .compare_and_swap(oldkvs, newkvs, MEMORY_ORDERING)
-            == oldkvs
+            .compare_exchange(oldkvs, newkvs, MEMORY_ORDERING, MEMORY_ORDERING)
+            .is_ok()
         {
-            (*kvs)._chm._has_newkvs = true;
             self.rehash();
         } else {
+            // Lost the race, nobody else has seen our table
+            drop(Box::from_raw(newkvs));
             newkvs = (*kvs)._chm._newkvs.load(MEMORY_ORDERING);
         }
         newkvs
     }
 
-    pub fn put<'a>(&mut self, key: K, newval: V) -> &'a V {
-        unsafe { self.put_if_match(key, newval, MatchingTypes::MatchAll, None) }
+    fn since_last_resize(&self) -> Duration {
+        let last = Duration::from_millis(self._last_resize.load(MEMORY_ORDERING));
+        self._created
+            .elapsed()
+            .checked_sub(last)
+            .unwrap_or_default()
+    }
+
+    /// Maps `key` to `newval`, returning the value it replaces, like `HashMap::insert`.
+    ///
+    /// Other threads may still be reading the replaced value, so it is only lent out for as long
+    /// as `guard`.
+    pub fn insert<'g>(&'g self, key: K, newval: V, guard: &'g Guard) -> Option<&'g V> {
+        unsafe {
+            let oldval = self.put_if_match(
+                Box::into_raw(Box::new(Key::<K>::new(key))),
+                Box::into_raw(Box::new(Value::<V>::new(newval))),
+                MatchingTypes::MatchAll,
+                None,
+                guard,
+            );
+            Value::get_value(oldval)
+        }
+    }
+
+    /// Same as `insert`, returning a clone of the replaced value for callers that don't want
+    /// to hold a guard.
+    pub fn insert_cloned(&self, key: K, newval: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.insert(key, newval, &pin()).cloned()
+    }
+
+    /// Looks up `key` for in-place manipulation, references handed out are valid as long as
+    /// `guard`.
+    pub fn entry<'g>(&'g self, key: K, guard: &'g Guard) -> Entry<'g, K, V, S> {
+        Entry::new(self, key, guard)
+    }
+
+    /// Maps `key` to `newval` only if `key` is absent (never inserted, or removed).
+    ///
+    /// When several threads race to initialise the same key exactly one of them succeeds; every
+    /// other caller gets `Err` with the value that is in the map, valid as long as `guard`.
+    pub fn put_if_absent<'g>(&'g self, key: K, newval: V, guard: &'g Guard) -> Result<(), &'g V> {
+        unsafe {
+            self.put_if_absent_impl(
+                Box::into_raw(Box::new(Key::<K>::new(key))),
+                Box::into_raw(Box::new(Value::<V>::new(newval))),
+                guard,
+            )
+            .map(|_| ())
+        }
+    }
+
+    // Returns the value installed on success. Its payload stays put for as long as guard is
+    // pinned, even if a resize copies it into a new box.
+    unsafe fn put_if_absent_impl<'g>(
+        &self,
+        key: *mut Key<K>,
+        putval: *mut Value<V>,
+        guard: &'g Guard,
+    ) -> Result<&'g V, &'g V> {
+        let installed: *const V = (*putval).get();
+        let oldval = self.put_if_match(
+            key,
+            putval,
+            MatchingTypes::MatchValue,
+            // A TombStone expectation also matches an empty value slot
+            Some(Value::tombstone()),
+            guard,
+        );
+        match Value::get_value(oldval) {
+            Some(v) => Err(v),
+            None => Ok(&*installed),
+        }
+    }
+
+    /// Maps `key` to `newval` only if `key` currently has a value, returning the replaced value.
+    ///
+    /// Other threads may still be reading the replaced value, so it is only lent out for as long
+    /// as `guard`.
+    pub fn replace<'g>(&'g self, key: K, newval: V, guard: &'g Guard) -> Option<&'g V> {
+        unsafe {
+            let oldval = self.put_if_match(
+                Box::into_raw(Box::new(Key::<K>::new(key))),
+                Box::into_raw(Box::new(Value::<V>::new(newval))),
+                MatchingTypes::MatchAllNotEmpty,
+                None,
+                guard,
+            );
+            Value::get_value(oldval)
+        }
+    }
+
+    /// Same as `replace`, returning a clone of the replaced value.
+    pub fn replace_cloned(&self, key: K, newval: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.replace(key, newval, &pin()).cloned()
     }
 
-    unsafe fn put_if_match<'a>(
-        &mut self,
+    /// Maps `key` to `newval` only if its current value equals `expected`.
+    ///
+    /// Values are compared with `Eq`, so any value equal to `expected` is replaced, even one put
+    /// after `expected` was read. On failure the value that is currently in the map, if any, is returned in `Err`, valid as
+    /// long as `guard`.
+    pub fn compare_and_set<'g>(
+        &'g self,
         key: K,
+        expected: &V,
         newval: V,
-        matchingtype: MatchingTypes,
-        expval: Option<V>,
-    ) -> &'a V {
-        let table = self.get_table_nonatomic();
-        self.put_if_match_to_kvs(table, key, newval, matchingtype, expval)
+        guard: &'g Guard,
+    ) -> Result<(), Option<&'g V>> {
+        unsafe {
+            self.compare_and_set_impl(
+                Box::into_raw(Box::new(Key::<K>::new(key))),
+                expected,
+                newval,
+                MatchingTypes::MatchValue,
+                guard,
+            )
+            .map(|_| ())
+        }
     }
 
-    unsafe fn put_if_match_to_kvs<'a>(
-        &mut self,
-        kvs: *mut KVs<K, V>,
-        key: K,
+    // Matches `expected` by value or, for MatchPayload, only the very payload it points to.
+    // Returns the value installed on success, see put_if_absent_impl.
+    unsafe fn compare_and_set_impl<'g>(
+        &self,
+        key: *mut Key<K>,
+        expected: &V,
         newval: V,
         matchingtype: MatchingTypes,
-        expval: Option<V>,
-    ) -> &'a V {
-        let new_expval = expval.map(|v| Box::into_raw(Box::new(Value::<V>::new(v))));
-        let returnval = self.put_if_match_impl(
-            kvs,
-            Box::into_raw(Box::new(Key::<K>::new(key))),
-            Box::into_raw(Box::new(Value::<V>::new(newval))),
-            matchingtype,
-            new_expval,
-        );
-        &(*(*returnval)._value)
+        guard: &'g Guard,
+    ) -> Result<&'g V, Option<&'g V>> {
+        let exact = matchingtype == MatchingTypes::MatchPayload;
+        let mut expval = Value::<V>::new_borrowed(expected);
+        let putval = Box::into_raw(Box::new(Value::<V>::new(newval)));
+        let installed: *const V = (*putval).get();
+        let oldval = self.put_if_match(key, putval, matchingtype, Some(&mut expval), guard);
+        match Value::get_value(oldval) {
+            Some(v) if exact && ptr::eq(v, expected) => Ok(&*installed),
+            Some(v) if !exact && v == expected => Ok(&*installed),
+            Some(v) => Err(Some(v)),
+            None => Err(None),
+        }
+    }
+
+    /// Atomically maps `key` to `f` of its current value, or removes it if `f` returns `None`.
+    ///
+    /// The result of `f` is only put if the very value it was given is still current, not just an
+    /// equal one; otherwise `f` is called again with the new current value, so it may run several
+    /// times. Returns the
+    /// value the key ends up mapped to, valid as long as `guard`.
+    pub fn update<'g, F>(&'g self, key: K, mut f: F, guard: &'g Guard) -> Option<&'g V>
+    where
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        let key = Key::<K>::new(key);
+        loop {
+            let curval = self.get(key.get(), guard);
+            unsafe {
+                match (curval, f(curval)) {
+                    (None, None) => return None,
+                    (None, Some(newval)) => {
+                        let putkey = Box::into_raw(Box::new(key.share()));
+                        let putval = Box::into_raw(Box::new(Value::<V>::new(newval)));
+                        if let Ok(v) = self.put_if_absent_impl(putkey, putval, guard) {
+                            return Some(v);
+                        }
+                    }
+                    (Some(curval), Some(newval)) => {
+                        let putkey = Box::into_raw(Box::new(key.share()));
+                        if let Ok(v) = self.compare_and_set_impl(
+                            putkey,
+                            curval,
+                            newval,
+                            MatchingTypes::MatchPayload,
+                            guard,
+                        ) {
+                            return Some(v);
+                        }
+                    }
+                    (Some(curval), None) => {
+                        match self.remove_impl(key.get(), Some(curval), guard) {
+                            Some(v) if ptr::eq(v, curval) => return None,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Maps `key` to `value` if it is absent, otherwise atomically to `combine(current, &value)`.
+    ///
+    /// Like `update`, `combine` is called again if the current value was replaced, even by an
+    /// equal one, before the combined one could be put, so concurrent merges into the same key
+    /// are never lost.
+    /// Returns the value the key ends up mapped to, valid as long as `guard`.
+    pub fn merge<'g, F>(&'g self, key: K, value: V, mut combine: F, guard: &'g Guard) -> &'g V
+    where
+        F: FnMut(&V, &V) -> V,
+    {
+        let key = Key::<K>::new(key);
+        let value = Value::<V>::new(value);
+        loop {
+            let putkey = Box::into_raw(Box::new(key.share()));
+            unsafe {
+                match self.get(key.get(), guard) {
+                    None => {
+                        let putval = Box::into_raw(Box::new(value.share()));
+                        if let Ok(v) = self.put_if_absent_impl(putkey, putval, guard) {
+                            return v;
+                        }
+                    }
+                    Some(curval) => {
+                        let newval = combine(curval, value.get());
+                        if let Ok(v) = self.compare_and_set_impl(
+                            putkey,
+                            curval,
+                            newval,
+                            MatchingTypes::MatchPayload,
+                            guard,
+                        ) {
+                            return v;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the value mapped to `key`, first mapping it to `f()` if it is absent.
+    ///
+    /// Unlike `entry(key).or_insert_with(f)`, `f` runs once however many threads miss on the key
+    /// at the same time: the first one puts a pending value in the slot and computes the real
+    /// one, the others sleep until it is there. Neither keeps `guard` pinned meanwhile, which is
+    /// why it is borrowed mutably; it is pinned again to look the value up.
+    ///
+    /// Every other operation treats a pending key as absent. If one of them maps or removes the
+    /// key meanwhile, what it put wins over `f()`, and after a removal a waiter may have to run
+    /// its own `f`. If `f` panics the key is left absent and one of the waiters takes over.
+    pub fn get_or_insert_with<'g, F>(&'g self, key: K, f: F, guard: &'g mut Guard) -> &'g V
+    where
+        F: FnOnce() -> V,
+    {
+        let key = Key::<K>::new(key);
+        unsafe {
+            loop {
+                let curval = self
+                    .get_impl(self.get_table_nonatomic(), key.get(), guard)
+                    .map_or(Value::empty(), |(_, v)| v);
+                if !Value::is_pending(curval) {
+                    if let Some(v) = Value::get_value(curval) {
+                        return v;
+                    }
+                    // Putting a pending value over another one changes nothing and returns it
+                    let oldval = self.put_if_match(
+                        Box::into_raw(Box::new(key.share())),
+                        Value::pending(),
+                        MatchingTypes::MatchValue,
+                        Some(Value::tombstone()),
+                        guard,
+                    );
+                    if let Some(v) = Value::get_value(oldval) {
+                        return v;
+                    }
+                    if !Value::is_pending(oldval) {
+                        break;
+                    }
+                }
+                guard.repin_after(|| self._waiters.wait_while(|| self.is_pending(key.get())));
+            }
+
+            let value = guard.repin_after(|| {
+                let pending = PendingValue {
+                    map: self,
+                    key: key.get(),
+                };
+                let value = f();
+                mem::forget(pending);
+                value
+            });
+            let value = Value::<V>::new(value);
+            let putval = Box::into_raw(Box::new(value.share()));
+            let installed: *const V = (*putval).get();
+            let oldval = self.put_if_match(
+                Box::into_raw(Box::new(key.share())),
+                putval,
+                MatchingTypes::MatchValue,
+                Some(Value::pending()),
+                guard,
+            );
+            let v = if Value::is_pending(oldval) {
+                &*installed
+            } else {
+                // The key was mapped or removed meanwhile
+                match self.put_if_absent_impl(
+                    Box::into_raw(Box::new(key.share())),
+                    Box::into_raw(Box::new(value.share())),
+                    guard,
+                ) {
+                    Ok(v) | Err(v) => v,
+                }
+            };
+            self._waiters.wake_all();
+            v
+        }
+    }
+
+    // Whether key is mapped to a value that get_or_insert_with is still computing.
+    fn is_pending(&self, key: &K) -> bool {
+        let guard = &pin();
+        unsafe {
+            self.get_impl(self.get_table_nonatomic(), key, guard)
+                .is_some_and(|(_, v)| Value::is_pending(v))
+        }
+    }
+
+    /// Removes `key` from the map, returning the value it was mapped to.
+    ///
+    /// The key may be any borrowed form of the map's key type, as for `get`. Other threads may
+    /// still be reading the removed value, so it is only lent out for as long as `guard`.
+    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        unsafe { self.remove_borrowed(key, guard).map(|(_, v)| v) }
+    }
+
+    /// Same as `remove`, returning a clone of the removed value.
+    pub fn remove_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.remove(key, &pin()).cloned()
+    }
+
+    /// Removes `key` from the map, returning the key that was in the map and its value, both
+    /// valid as long as `guard`.
+    pub fn remove_entry<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<(&'g K, &'g V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        unsafe { self.remove_borrowed(key, guard) }
+    }
+
+    /// Same as `remove_entry`, returning clones of the removed key and value.
+    pub fn remove_entry_cloned<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        K: Clone,
+        V: Clone,
+    {
+        self.remove_entry(key, &pin())
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    // remove_impl only probes with a K, so look up the key that is in the table first. Any key
+    // equal to it removes the same entry, even if it was removed and inserted again meanwhile.
+    unsafe fn remove_borrowed<'g, Q>(&self, key: &Q, guard: &'g Guard) -> Option<(&'g K, &'g V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (k, _) = self.get_impl(self.get_table_nonatomic(), key, guard)?;
+        let k: &'g K = (*k).get();
+        self.remove_impl(k, None, guard).map(|v| (k, v))
+    }
+
+    /// Removes every entry by swapping in an empty table.
+    ///
+    /// Concurrent readers see either the old entries or none. An insert racing with `clear` may
+    /// still land in the old table and be discarded with it, as if it had happened just before.
+    pub fn clear(&self) {
+        let guard = &pin();
+        let newkvs = Box::into_raw(Box::new(KVs::<K, V>::new(MIN_SIZE)));
+        let mut kvs = self._kvs.load(MEMORY_ORDERING);
+        while let Err(cur) =
+            self._kvs
+                .compare_exchange(kvs, newkvs, MEMORY_ORDERING, MEMORY_ORDERING)
+        {
+            kvs = cur;
+        }
+        unsafe { retire_chain(guard, kvs) };
+    }
+
+    /// Removes every entry for which `f` returns `false`.
+    ///
+    /// Entries are visited as by `iter` while other threads keep using the map. An entry is only
+    /// removed if its value is still the very one `f` was shown, so a value replaced meanwhile is
+    /// kept, even by an equal one.
+    pub fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let guard = &pin();
+        for (key, value) in self.iter(guard) {
+            if !f(key, value) {
+                unsafe { self.remove_impl(key, Some(value), guard) };
+            }
+        }
+    }
+
+    // {Key, Value} -> {Key, ValueTombStone}, only from the very payload `expected` if given.
+    // A tombstone is never put into an empty key slot, so the borrowed key is only used for
+    // probing and never ends up in the table.
+    unsafe fn remove_impl<'g>(
+        &self,
+        key: &K,
+        expected: Option<&V>,
+        guard: &'g Guard,
+    ) -> Option<&'g V> {
+        let mut probe = Key::<K>::new_borrowed(key);
+        let oldval = match expected {
+            Some(expected) => {
+                let mut expval = Value::<V>::new_borrowed(expected);
+                self.put_if_match(
+                    &mut probe,
+                    Value::tombstone(),
+                    MatchingTypes::MatchPayload,
+                    Some(&mut expval),
+                    guard,
+                )
+            }
+            None => self.put_if_match(
+                &mut probe,
+                Value::tombstone(),
+                MatchingTypes::MatchAll,
+                None,
+                guard,
+            ),
+        };
+        Value::get_value(oldval)
+    }
+
+    unsafe fn put_if_match(
+        &self,
+        key: *mut Key<K>,
+        putval: *mut Value<V>,
+        matchingtype: MatchingTypes,
+        expval: Option<*mut Value<V>>,
+        guard: &Guard,
+    ) -> *mut Value<V> {
+        let table = self.get_table_nonatomic();
+        self.put_if_match_impl(table, key, putval, matchingtype, expval, guard)
     }
 
+    // Ownership: putval always ends up either in the table or freed. So does key, except for
+    // tombstone puts, which never claim a key slot and leave key to the caller. The returned
+    // value (the one that was in the slot) is only valid as long as guard is pinned.
     // FIXME: clippy::cyclomatic_complexity: the function has a cyclomatic complexity of 26
     unsafe fn put_if_match_impl(
-        &mut self,
+        &self,
         kvs: *mut KVs<K, V>,
         key: *mut Key<K>,
         putval: *mut Value<V>,
         matchingtype: MatchingTypes,
         expval: Option<*mut Value<V>>,
+        guard: &Guard,
     ) -> *mut Value<V> {
         //let mut debugval = 0 as *mut Value<V>;
         //if expval.is_some() { debugval = expval.unwrap() }
-        assert!(!(*putval).is_empty()); // Never put a ValueEmpty type
-        assert!(!(*putval).is_prime()); // Never put a Prime type
-        assert!(matchingtype != MatchingTypes::MatchValue || !expval.is_none()); // If matchingtype==MatchValue then expval must contain something
-        if expval.is_some() {
-            assert!(!(*expval.unwrap()).is_prime());
+        assert!(!Value::is_empty(putval)); // Never put a ValueEmpty type
+        assert!(!Value::is_prime(putval)); // Never put a Prime type
+        assert!(
+            (matchingtype != MatchingTypes::MatchValue
+                && matchingtype != MatchingTypes::MatchPayload)
+                || expval.is_some()
+        ); // If matchingtype==MatchValue then expval must contain something
+        if let Some(expval) = expval {
+            assert!(!Value::is_prime(expval));
         } // Never expect a Prime type
 
-        let mut hasher = DefaultHasher::new();
-        (*key).hash(&mut hasher);
-        let fullhash = hasher.finish();
+        let fullhash = self._hasher.hash_one(&*key);
         let len = (*kvs).len();
         let mut idx: usize = fullhash as usize & (len - 1);
         let mut reprobe_cnt: usize = 0;
@@ -224,42 +856,60 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         // Determine if expval is empty
         let mut expval_not_empty = false;
         if matchingtype == MatchingTypes::MatchValue {
-            if !(*expval.unwrap()).is_empty() {
+            if !Value::is_empty(expval.unwrap()) {
                 expval_not_empty = true;
             }
         } else {
             expval_not_empty = true;
         }
+        let owns_key = !Value::is_tombstone(putval);
+        let mut key_installed = false;
+        // Frees whatever did not make it into the table
+        let discard = |key_installed: bool, putval_installed: bool| {
+            if owns_key && !key_installed {
+                drop(Box::from_raw(key));
+            }
+            if !putval_installed {
+                Value::free(putval);
+            }
+        };
         // Probing/Re-probing
         loop {
-            if (*k).is_empty() {
+            if Key::is_empty(k) {
                 // Found an available key slot
-                if (*putval).is_tombstone() {
-                    return putval;
+                if Value::is_tombstone(putval) {
+                    discard(false, false);
+                    return v;
                 } // Never change KeyEmpty to KeyTombStone
-                if (*kvs)._ks[idx].compare_and_swap(k, key, MEMORY_ORDERING) == k {
+                if matchingtype == MatchingTypes::MatchAllNotEmpty {
+                    discard(false, false);
+                    return v;
+                } // The key is not here, so there is nothing to replace
+                if (*kvs).cas_key(idx, k, key) {
                     // Add key to the slot
-                    (*kvs)._chm._slots.fetch_add(1, MEMORY_ORDERING); // Add 1 to the number of used slots
-                    (*kvs)._hashes[idx] = fullhash;
+                    (*kvs)._chm._slots.increment(); // Add 1 to the number of used slots
+                    (*kvs).set_hash(idx, fullhash);
+                    key_installed = true;
                     break;
                 }
                 k = (*kvs).get_key_nonatomic_at(idx);
                 v = (*kvs).get_value_nonatomic_at(idx);
-                assert!(!(*k).is_empty());
+                assert!(!Key::is_empty(k));
             }
             //fence(MEMORY_ORDERING);
-            if k == key || (*k) == (*key) {
+            if Self::fast_keyeq(k, (*kvs).get_hash(idx), key, fullhash) {
                 break;
             }
             // Start re-probing
             reprobe_cnt += 1;
-            if reprobe_cnt >= REPROBE_LIMIT || (*key).is_tombstone() {
+            if reprobe_cnt >= (*kvs).reprobe_limit() || Key::is_tombstone(key) {
                 // Enter state {KeyTombStone, Empty}; steal exucution path for optimization; let helper save the day.
                 let newkvs = self.resize(kvs);
                 if expval_not_empty {
-                    self.help_copy();
+                    self.help_copy(guard);
                 }
-                return self.put_if_match_impl(newkvs, key, putval, matchingtype, expval); // Put in the new table instead
+                // Put in the new table instead
+                return self.put_if_match_impl(newkvs, key, putval, matchingtype, expval, guard);
             }
             idx = (idx + 1) & (len - 1);
             k = (*kvs).get_key_nonatomic_at(idx);
@@ -267,12 +917,24 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         }
         // End probe/re-probing
 
-        if (*putval) == (*v) {
+        // A key that is already in this table cannot go into the next one as well
+        let next_key = || {
+            if key_installed {
+                Box::into_raw(Box::new((*key).share()))
+            } else {
+                key
+            }
+        };
+
+        // Only sentinels are ever the same as putval. An equal value is put all the same, it may
+        // differ in whatever its Eq ignores.
+        if putval == v {
+            discard(key_installed, false);
             return v;
         } // Steal path exucution for optimization; let helper save the day.
         if (*kvs)._chm.has_newkvs()
-            && (( (*v).is_tombstone() && (*kvs).table_full(reprobe_cnt) ) || // Resize if the table is full.
-                (*v).is_prime())
+            && (( Value::is_tombstone(v) && (*kvs).table_full(reprobe_cnt) ) || // Resize if the table is full.
+                Value::is_prime(v))
         // I don't understand this, but I take it from the original code anyway. It is some sort of invalid state caused by compilier's optimization.
         {
             self.resize(kvs);
@@ -281,109 +943,162 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             // Check for the last time if kvs is the newest table
             let expval_is_empty = {
                 match expval {
-                    Some(val) => (*val).is_empty(),
+                    Some(val) => Value::is_empty(val),
                     None => true,
                 }
             };
-            let copied_kvs = self.copy_slot_and_check(kvs, idx, !expval_is_empty); // If expval is empty then don't help (expval is empty only if this function is called from copy_slot)
-            return self.put_if_match_impl(copied_kvs, key, putval, matchingtype, expval);
+            let copied_kvs = self.copy_slot_and_check(kvs, idx, !expval_is_empty, guard); // If expval is empty then don't help (expval is empty only if this function is called from copy_slot)
+            return self.put_if_match_impl(
+                copied_kvs,
+                next_key(),
+                putval,
+                matchingtype,
+                expval,
+                guard,
+            );
         }
 
         // This table is the newest, so we can start entering the state machine.
         loop {
-            assert!(!(*v).is_prime()); // If there is a Prime than this cannot be the newest table.
+            assert!(!Value::is_prime(v)); // If there is a Prime than this cannot be the newest table.
+            if matchingtype == MatchingTypes::MatchAllNotEmpty && Value::is_absent(v) {
+                discard(key_installed, false);
+                return v; // Only replace a live value
+            }
             if matchingtype!=MatchingTypes::MatchAll && // If expval is not a wildcard
-                !( matchingtype==MatchingTypes::MatchAllNotEmpty && !(*v).is_tombstone() && !(*v).is_empty() )
+                ( matchingtype!=MatchingTypes::MatchAllNotEmpty || Value::is_absent(v) )
             // If expval is not a TombStone or Empty
             {
-                assert!(!expval.is_none());
-                assert!(matchingtype == MatchingTypes::MatchValue);
+                assert!(expval.is_some());
+                let matches = match matchingtype {
+                    MatchingTypes::MatchValue => Value::same(expval.unwrap(), v),
+                    MatchingTypes::MatchPayload => Value::same_payload(expval.unwrap(), v),
+                    _ => unreachable!(),
+                };
                 if v!=expval.unwrap() && // if v!= expval (pointer)
-                    !((*v).is_empty() && (*expval.unwrap()).is_tombstone()) && // If we expect a TombStone and v is empty, it should be a match.
-                    *expval.unwrap()!=*v
+                    !(Value::is_absent(v) && Value::is_tombstone(expval.unwrap())) && // If we expect a TombStone and v is empty or pending, it should be a match.
+                    !matches
                 // expval==Empty or *expval==*v
                 {
+                    discard(key_installed, false);
                     return v; // do nothing, just return the old value.
                 }
             }
 
             // Finally, add some values.
-            if (*kvs)._vs[idx].compare_and_swap(v, putval, MEMORY_ORDERING) == v {
+            if (*kvs).cas_value(idx, v, putval) {
                 if expval_not_empty {
-                    if ((*v).is_empty() || (*v).is_tombstone()) && !(*putval).is_tombstone() {
-                        (*kvs)._chm._size.fetch_add(1, MEMORY_ORDERING);
+                    if Value::is_absent(v) && !Value::is_absent(putval) {
+                        (*kvs)._chm._size.increment();
                     }
-                    if !((*v).is_empty() || (*v).is_tombstone()) && (*putval).is_tombstone() {
-                        (*kvs)._chm._size.fetch_sub(1, MEMORY_ORDERING);
+                    if !Value::is_absent(v) && Value::is_absent(putval) {
+                        (*kvs)._chm._size.decrement();
                     }
                 }
-                if (*v).is_empty() && expval_not_empty {
-                    return Box::into_raw(Box::new(Value::<V>::new_tombstone()));
-                } else {
-                    return v;
-                }
+                discard(key_installed, true);
+                retire_value(guard, v);
+                return v;
             }
             v = (*kvs).get_value_nonatomic_at(idx);
-            if (*v).is_prime() {
-                let copied_kvs = self.copy_slot_and_check(kvs, idx, expval_not_empty);
-                return self.put_if_match_impl(copied_kvs, key, putval, matchingtype, expval);
+            if Value::is_prime(v) {
+                let copied_kvs = self.copy_slot_and_check(kvs, idx, expval_not_empty, guard);
+                return self.put_if_match_impl(
+                    copied_kvs,
+                    next_key(),
+                    putval,
+                    matchingtype,
+                    expval,
+                    guard,
+                );
             }
         }
     }
 
-    pub fn get(&mut self, key: K) -> Option<&V> {
+    /// Returns a reference to the value mapped to `key`.
+    ///
+    /// The key may be any borrowed form of the map's key type, e.g. `&str` for `String` keys.
+    /// The value may be replaced and freed by other threads, so the reference only lives as
+    /// long as `guard`.
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
         let table = self.get_table_nonatomic();
-        let maybe_val =
-            // FIXME: the new boxed key will be leaked after into_raw()!
-            // plus, there's no need to wrap key in Key<K> in get() at all.
-            unsafe { self.get_impl(table, Box::into_raw(Box::new(Key::<K>::new(key)))) };
-        maybe_val.map(|v| unsafe { &*(*v)._value })
+        let maybe_val = unsafe { self.get_impl(table, key, guard) };
+        maybe_val.and_then(|(_, v)| unsafe { Value::get_value(v) })
     }
 
-    // Compute hash only once
-    unsafe fn get_impl(&mut self, kvs: *mut KVs<K, V>, key: *mut Key<K>) -> Option<*mut Value<V>> {
-        let mut hasher = DefaultHasher::new();
-        (*key).hash(&mut hasher);
-        let fullhash = hasher.finish();
-        self.get_impl_supply_hash(kvs, key, fullhash)
+    /// Returns a clone of the value mapped to `key`, for callers that don't want to hold a guard.
+    pub fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.get(key, &self.guard()).cloned()
     }
 
-    unsafe fn get_impl_supply_hash(
-        &mut self,
+    // Compute hash only once. Also returns the key found in the table, to probe with when key is
+    // only a borrowed form of it.
+    unsafe fn get_impl<Q>(
+        &self,
         kvs: *mut KVs<K, V>,
-        key: *mut Key<K>,
+        key: &Q,
+        guard: &Guard,
+    ) -> Option<(*mut Key<K>, *mut Value<V>)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let fullhash = self._hasher.hash_one(key);
+        self.get_impl_supply_hash(kvs, key, fullhash, guard)
+    }
+
+    unsafe fn get_impl_supply_hash<Q>(
+        &self,
+        kvs: *mut KVs<K, V>,
+        key: &Q,
         fullhash: u64,
-    ) -> Option<*mut Value<V>> {
+        guard: &Guard,
+    ) -> Option<(*mut Key<K>, *mut Value<V>)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
         let len = (*kvs).len();
         let mut idx = (fullhash & (len - 1) as u64) as usize;
         let mut reprobe_cnt: usize = 0;
         loop {
             let k = (*kvs).get_key_nonatomic_at(idx);
             let v = (*kvs).get_value_nonatomic_at(idx);
-            if (*k).is_empty() {
+            if Key::is_empty(k) {
                 return None;
             }
             //fence(MEMORY_ORDERING);
-            if (*k) == (*key) {
-                if !(*v).is_prime() {
-                    if (*v).is_tombstone() {
+            // A zero hash has not been published yet, so the key has to be compared in full
+            let hashk = (*kvs).get_hash(idx);
+            if (hashk == 0 || hashk == fullhash) && !Key::is_tombstone(k) && (*k).matches(key) {
+                if !Value::is_prime(v) {
+                    if Value::is_tombstone(v) || Value::is_empty(v) {
                         return None;
                     } else {
-                        return Some(v);
+                        return Some((k, v));
                     }
                 } else {
-                    let table = self.copy_slot_and_check(kvs, idx, true);
-                    return self.get_impl_supply_hash(table, key, fullhash);
+                    let table = self.copy_slot_and_check(kvs, idx, true, guard);
+                    return self.get_impl_supply_hash(table, key, fullhash, guard);
                 }
             }
             reprobe_cnt += 1;
-            if reprobe_cnt >= REPROBE_LIMIT || (*k).is_tombstone() {
+            if reprobe_cnt >= (*kvs).reprobe_limit() || Key::is_tombstone(k) {
                 if (*kvs)._chm.has_newkvs() {
-                    self.help_copy();
+                    self.help_copy(guard);
                     return self.get_impl_supply_hash(
                         (*kvs)._chm.get_newkvs_nonatomic(),
                         key,
                         fullhash,
+                        guard,
                     );
                 } else {
                     return None;
@@ -394,63 +1109,75 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
     }
 
     unsafe fn copy_slot_and_check(
-        &mut self,
+        &self,
         oldkvs: *mut KVs<K, V>,
         idx: usize,
         should_help: bool,
+        guard: &Guard,
     ) -> *mut KVs<K, V> {
         //fence(MEMORY_ORDERING);
         assert!(!(*oldkvs)._chm.get_newkvs_nonatomic().is_null());
-        if self.copy_slot(oldkvs, idx) {
-            self.copy_check_and_promote(oldkvs, 1);
+        if self.copy_slot(oldkvs, idx, guard) {
+            self.copy_check_and_promote(oldkvs, 1, guard);
         }
 
         if should_help {
-            self.help_copy();
+            self.help_copy(guard);
         }
         (*oldkvs)._chm.get_newkvs_nonatomic()
     }
 
-    unsafe fn copy_check_and_promote(&mut self, oldkvs: *mut KVs<K, V>, work_done: usize) {
+    unsafe fn copy_check_and_promote(
+        &self,
+        oldkvs: *mut KVs<K, V>,
+        work_done: usize,
+        guard: &Guard,
+    ) {
         let oldlen = (*oldkvs).len();
         let mut copy_done = (*oldkvs)._chm._copy_done.load(MEMORY_ORDERING);
         assert!(copy_done + work_done <= oldlen);
         if work_done > 0 {
-            while (*oldkvs)._chm._copy_done.compare_and_swap(
+            while let Err(cur) = (*oldkvs)._chm._copy_done.compare_exchange(
                 copy_done,
                 copy_done + work_done,
                 MEMORY_ORDERING,
-            ) != copy_done
-            {
-                copy_done = (*oldkvs)._chm._copy_done.load(MEMORY_ORDERING);
+                MEMORY_ORDERING,
+            ) {
+                copy_done = cur;
             }
             assert!(copy_done + work_done <= oldlen);
         }
 
         if copy_done + work_done == oldlen
             && self._kvs.load(MEMORY_ORDERING) == oldkvs
-            && (self._kvs.compare_and_swap(
-                oldkvs,
-                (*oldkvs)._chm.get_newkvs_nonatomic(),
-                MEMORY_ORDERING,
-            ) == oldkvs)
+            && self
+                ._kvs
+                .compare_exchange(
+                    oldkvs,
+                    (*oldkvs)._chm.get_newkvs_nonatomic(),
+                    MEMORY_ORDERING,
+                    MEMORY_ORDERING,
+                )
+                .is_ok()
         {
             //println!("---obsolete---")
             //print_kvs(oldkvs);
-            self._last_resize = Instant::now();
+            let now = self._created.elapsed().as_millis() as u64;
+            self._last_resize.store(now, MEMORY_ORDERING);
+            // Only tombstones and the Keys of copied entries are left in the old table, every
+            // live entry now has its own Key and Value in the new one.
+            retire(guard, oldkvs);
         }
     }
 
-    unsafe fn copy_slot(&mut self, oldkvs: *mut KVs<K, V>, idx: usize) -> bool {
+    unsafe fn copy_slot(&self, oldkvs: *mut KVs<K, V>, idx: usize, guard: &Guard) -> bool {
         let mut key = (*oldkvs).get_key_nonatomic_at(idx);
 
         // State transition: {Empty, Empty} -> {KeyTombStone, Empty}
         // ---------------------------------------------------------
-        let tombstone_ptr: *mut Key<K> = Box::into_raw(Box::new(Key::<K>::new_tombstone()));
-        while (*key).is_empty() {
-            if (*oldkvs)._ks[idx].compare_and_swap(key, tombstone_ptr, MEMORY_ORDERING) == key {
+        while Key::is_empty(key) {
+            if (*oldkvs).cas_key(idx, key, Key::tombstone()) {
                 // Attempt {Empty, Empty} -> {KeyTombStone, Empty}
-                // FIXME: key is leaked (slot replaced by newly allocated tomestone)
                 return true;
             }
             key = (*oldkvs).get_key_nonatomic_at(idx);
@@ -459,32 +1186,29 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
 
         // Enter state: {KeyTombStone, Empty}
         // ---------------------------------------------------------
-        if (*key).is_tombstone() {
+        if Key::is_tombstone(key) {
             return false;
         }
         // ---------------------------------------------------------
 
-        // State transition: {Key, Empty} -> {Key, ValueTombPrime} or {Key, ValueTombStone} -> {Key, ValueTombPrime} or {Key, Value}->{Key, Value.get_prime()}
+        // State transition: {Key, Empty} -> {Key, ValueTombPrime} or {Key, ValueTombStone} -> {Key, ValueTombPrime} or {Key, Value}->{Key, Value.prime()}
         // -------------------------------------------------------------------------------------------------------
-        let tombstone_ptr = Value::<V>::new_tombstone().get_prime();
         let mut oldvalue = (*oldkvs).get_value_nonatomic_at(idx);
-        while !(*oldvalue).is_prime() {
+        while !Value::is_prime(oldvalue) {
             let primed = {
-                if (*oldvalue).is_empty() {
-                    tombstone_ptr
+                if Value::is_empty(oldvalue) {
+                    Value::tombprime()
                 } else {
-                    (*oldvalue).get_prime()
+                    Value::prime(oldvalue)
                 }
             };
-            if (*oldkvs)._vs[idx].compare_and_swap(oldvalue, primed, MEMORY_ORDERING) == oldvalue {
-                if (*primed).valuetype() == ValueTombStone {
-                    // FIXME: oldvalue leaked
+            if (*oldkvs).cas_value(idx, oldvalue, primed) {
+                if Value::is_tombprime(primed) {
                     return true;
                 }
                 // Transition: {Key, Empty} -> {Key, ValueTombPrime} or {Key, ValueTombStone} -> {Key, ValueTombPrime}
                 else {
                     // Transition: {Key, Value} -> {Key, Value'}
-                    // FIXME: oldvalue leaked
                     oldvalue = primed;
                     break;
                 }
@@ -493,39 +1217,40 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         }
         // -------------------------------------------------------------------------------------------------------
 
-        let tombprime = Value::<V>::new_tombprime();
-
         // Enter state: {Key, ValueTombPrime}
         // ---------------------------------------------------------
-        if (*oldvalue).is_tombprime() {
+        if Value::is_tombprime(oldvalue) {
             return false;
         }
         // ---------------------------------------------------------
 
-        // State transition: {Key, Value.get_prime()} -> {KeyTombStone, ValueTombPrime}
+        // State transition: {Key, Value.prime()} -> {KeyTombStone, ValueTombPrime}
         // ---------------------------------------------------------
-        let old_unprimed = (*oldvalue).get_unprime();
-        assert!((*old_unprimed) != tombprime);
+        let old_unprimed = Value::unprime(oldvalue);
         let newkvs = (*oldkvs)._chm.get_newkvs_nonatomic();
-        let emptyval: *mut Value<V> = Box::into_raw(Box::new(Value::<V>::new_empty()));
 
+        // The key and value stay in the old table until it is retired, the new table gets its
+        // own Key and Value sharing their payloads. A pending value is carried over as is, its
+        // waiters and the thread computing it follow the key into the new table.
+        let newvalue = if Value::is_pending(old_unprimed) {
+            Value::pending()
+        } else {
+            Box::into_raw(Box::new((*old_unprimed).share()))
+        };
         self.put_if_match_impl(
             newkvs,
-            key,
-            old_unprimed,
+            Box::into_raw(Box::new((*key).share())),
+            newvalue,
             MatchingTypes::MatchValue,
-            Some(emptyval),
+            Some(Value::empty()),
+            guard,
         );
 
-        let tombprime_ptr: *mut Value<V> = Box::into_raw(Box::new(Value::<V>::new_tombprime()));
-
-        // Enter state: {Key, Value.get_prime()} (intermediate)
+        // Enter state: {Key, Value.prime()} (intermediate)
         oldvalue = (*oldkvs).get_value_nonatomic_at(idx); // Check again, just in case...
-        while !(*oldvalue).is_tombprime() {
-            if (*oldkvs)._vs[idx].compare_and_swap(oldvalue, tombprime_ptr, MEMORY_ORDERING)
-                == oldvalue
-            {
-                // FIXME: oldvalue leaked
+        while !Value::is_tombprime(oldvalue) {
+            if (*oldkvs).cas_value(idx, oldvalue, Value::tombprime()) {
+                retire_value(guard, oldvalue);
                 return true;
             }
             oldvalue = (*oldkvs).get_value_nonatomic_at(idx);
@@ -535,14 +1260,14 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         false // State jump to {KeyTombStone, ValueTombPrime} for threads that lost the competition
     }
 
-    unsafe fn help_copy(&mut self) {
+    unsafe fn help_copy(&self, guard: &Guard) {
         if (*self.get_table_nonatomic())._chm.has_newkvs() {
             let kvs: *mut KVs<K, V> = self.get_table_nonatomic();
-            self.help_copy_impl(kvs, false);
+            self.help_copy_impl(kvs, false, guard);
         }
     }
 
-    unsafe fn help_copy_impl(&mut self, oldkvs: *mut KVs<K, V>, copy_all: bool) {
+    unsafe fn help_copy_impl(&self, oldkvs: *mut KVs<K, V>, copy_all: bool, guard: &Guard) {
         //fence(MEMORY_ORDERING);
         assert!((*oldkvs)._chm.has_newkvs());
         let oldlen = (*oldkvs).len();
@@ -553,14 +1278,16 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         while (*oldkvs)._chm._copy_done.load(MEMORY_ORDERING) < oldlen {
             if !panic_start {
                 copy_idx = (*oldkvs)._chm._copy_idx.load(MEMORY_ORDERING);
-                while copy_idx < oldlen << 1
-                    && (*oldkvs)._chm._copy_idx.compare_and_swap(
+                while copy_idx < oldlen << 1 {
+                    match (*oldkvs)._chm._copy_idx.compare_exchange(
                         copy_idx,
                         copy_idx + min_copy_work,
                         MEMORY_ORDERING,
-                    ) != copy_idx
-                {
-                    copy_idx = (*oldkvs)._chm._copy_idx.load(MEMORY_ORDERING);
+                        MEMORY_ORDERING,
+                    ) {
+                        Ok(_) => break,
+                        Err(cur) => copy_idx = cur,
+                    }
                 }
                 if copy_idx >= oldlen << 1 {
                     panic_start = true;
@@ -573,12 +1300,12 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             //}
             let mut work_done = 0;
             for i in 0..min_copy_work {
-                if self.copy_slot(oldkvs, (copy_idx + i) & (oldlen - 1)) {
+                if self.copy_slot(oldkvs, (copy_idx + i) & (oldlen - 1), guard) {
                     work_done += 1;
                 }
             }
             if work_done > 0 {
-                self.copy_check_and_promote(oldkvs, work_done);
+                self.copy_check_and_promote(oldkvs, work_done, guard);
             }
 
             copy_idx += min_copy_work;
@@ -587,11 +1314,11 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
                 return;
             }
         }
-        self.copy_check_and_promote(oldkvs, 0);
+        self.copy_check_and_promote(oldkvs, 0, guard);
     }
 
     pub fn get_kvs_level(&self, level: u32) -> Option<*mut KVs<K, V>> {
-        NonBlockingHashMap::get_kvs_level_impl(self.get_table_nonatomic(), level)
+        Self::get_kvs_level_impl(self.get_table_nonatomic(), level)
     }
 
     fn get_kvs_level_impl(kvs: *mut KVs<K, V>, level: u32) -> Option<*mut KVs<K, V>> {
@@ -601,79 +1328,266 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         if level == 0 {
             Some(kvs)
         } else {
-            unsafe {
-                NonBlockingHashMap::get_kvs_level_impl(
-                    (*kvs)._chm.get_newkvs_nonatomic(),
-                    level - 1,
-                )
-            }
+            unsafe { Self::get_kvs_level_impl((*kvs)._chm.get_newkvs_nonatomic(), level - 1) }
         }
     }
 
     unsafe fn fast_keyeq(k: *mut Key<K>, hashk: u64, key: *mut Key<K>, hashkey: u64) -> bool {
-        k == key || ((hashk == 0 || hashk == hashkey) && !(*k).is_tombstone() && (*key) == (*k))
+        k == key || ((hashk == 0 || hashk == hashkey) && !Key::is_tombstone(k) && (*key) == (*k))
     }
 
     pub fn rehash(&self) {}
 
     pub fn capacity(&self) -> usize {
+        let _guard = pin();
         unsafe { (*self._kvs.load(MEMORY_ORDERING)).len() }
     }
-}
 
-// debuging functions
-unsafe fn print_table<K: Eq + Hash + ToString, V: Eq + ToString>(table: &NonBlockingHashMap<K, V>) {
-    print_kvs(table.get_table_nonatomic());
-}
+    /// Returns the number of live entries in the map.
+    ///
+    /// All tables of a resize chain share one counter, so an in-progress resize does not affect
+    /// the result. The count is exact once concurrent updates have finished; while other
+    /// threads are inserting or removing it is only an approximation, because an entry is
+    /// counted shortly after it becomes visible.
+    pub fn len(&self) -> usize {
+        let _guard = pin();
+        let size = unsafe { (*self.get_table_nonatomic())._chm._size.get() };
+        // A remove can decrement the counter before the racing insert has incremented it.
+        size.max(0) as usize
+    }
 
-pub fn print_all<K: Eq + Hash + ToString, V: Eq + ToString>(table: &NonBlockingHashMap<K, V>) {
-    let mut kvs = table.get_table_nonatomic();
-    let mut i = 0;
-    while !kvs.is_null() {
-        println!("---Table {}---", i);
-        unsafe { print_kvs(kvs) };
-        i += 1;
-        kvs = unsafe { (*kvs)._chm.get_newkvs_nonatomic() };
+    /// Returns `true` if the map has no live entries; approximate in the same way as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
-}
 
-unsafe fn print_kvs<K: Eq + Hash + ToString, V: Eq + ToString>(kvs: *mut KVs<K, V>) {
-    for i in 0..(*kvs).len() {
-        print!(
-            "{}: ({}, ",
-            i,
-            key_to_string((*kvs).get_key_nonatomic_at(i))
-        );
-        print!("{}, ", value_to_string((*kvs).get_value_nonatomic_at(i)));
-        println!("{})", (*kvs)._hashes[i]);
+    /// Returns `true` if the map has a value for `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = &pin();
+        unsafe {
+            self.get_impl(self.get_table_nonatomic(), key, guard)
+                .is_some_and(|(_, v)| !Value::is_absent(v))
+        }
     }
-}
 
-unsafe fn key_to_string<K: Eq + Hash + ToString>(key: *mut Key<K>) -> String {
-    match (*key).keytype() {
-        KeyTombStone => String::from("TOMBSTONE"),
-        KeyEmpty => String::from("EMPTY"),
-        KeyType => {
-            assert!(!(*key)._key.is_null());
-            (*(*key)._key).to_string()
+    /// Returns an iterator over the entries of the map, valid as long as `guard`.
+    ///
+    /// The iterator is weakly consistent: concurrent updates never invalidate it, every key that
+    /// is in the map for the whole iteration is returned exactly once, and no key is returned
+    /// twice. Keys inserted or removed while iterating may or may not be seen, and a value is
+    /// whatever the key was mapped to when the iterator got to it.
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> Iter<'g, K, V, S> {
+        Iter::new(self, guard)
+    }
+
+    /// Returns an iterator over the keys of the map, with the guarantees of `iter`.
+    pub fn keys<'g>(&'g self, guard: &'g Guard) -> Keys<'g, K, V, S> {
+        Keys::new(self, guard)
+    }
+
+    /// Returns an iterator over the values of the map, with the guarantees of `iter`.
+    pub fn values<'g>(&'g self, guard: &'g Guard) -> Values<'g, K, V, S> {
+        Values::new(self, guard)
+    }
+
+    /// Returns an iterator that removes the entries of the map and yields them.
+    ///
+    /// The map stays usable meanwhile. Entries are visited as by `iter`, so every entry in the
+    /// map for the whole drain is yielded, with the value it had when it was removed; entries
+    /// inserted while draining may be left behind for the next drain. Other threads may still be
+    /// reading removed entries, so clones of them are yielded. Dropping the drain before it is
+    /// done removes the remaining entries without yielding them.
+    pub fn drain<'g>(&'g self, guard: &'g Guard) -> Drain<'g, K, V, S>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        Drain::new(self, guard)
+    }
+
+    /// Removes every entry of the map and returns them, see `drain`.
+    pub fn take_all(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.drain(&pin()).collect()
+    }
+
+    // Grows the table at once to fit `additional` more keys, as if the map had been created
+    // with room for them.
+    fn reserve(&mut self, additional: usize) {
+        let guard = &pin();
+        unsafe {
+            let kvs = self.snapshot(guard);
+            let size = table_size_for(self.len().saturating_add(additional));
+            if (*kvs).len() >= size {
+                return;
+            }
+            let newkvs = Box::into_raw(Box::new(KVs::<K, V>::new_with_size_counter(
+                size,
+                (*kvs)._chm._size.clone(),
+            )));
+            // Borrowed mutably, nobody else can be starting a resize of their own
+            (*kvs)._chm._newkvs.store(newkvs, MEMORY_ORDERING);
+            self.snapshot(guard);
         }
     }
-}
 
-unsafe fn value_to_string<V: Eq + ToString>(value: *mut Value<V>) -> String {
-    match (*value).valuetype() {
-        ValueTombStone => {
-            if (*value).is_prime() {
-                String::from("TOMBPRIME")
-            } else {
-                String::from("TOMBSTONE")
+    // The top table after finishing any copy in progress, so it holds every key in the map
+    unsafe fn snapshot(&self, guard: &Guard) -> *mut KVs<K, V> {
+        loop {
+            let kvs = self.get_table_nonatomic();
+            if !(*kvs)._chm.has_newkvs() {
+                return kvs;
+            }
+            self.help_copy_impl(kvs, true, guard);
+        }
+    }
+}
+
+// Takes a pending value back out of the map if the thread computing it panics, so that one of
+// the waiters can compute it instead.
+struct PendingValue<'a, K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher,
+{
+    map: &'a NonBlockingHashMap<K, V, S>,
+    key: &'a K,
+}
+
+impl<K, V, S> Drop for PendingValue<'_, K, V, S>
+where
+    K: Eq + Hash + Send + 'static,
+    V: Eq + Send + 'static,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        unsafe {
+            let mut probe = Key::<K>::new_borrowed(self.key);
+            self.map.put_if_match(
+                &mut probe,
+                Value::tombstone(),
+                MatchingTypes::MatchValue,
+                Some(Value::pending()),
+                &pin(),
+            );
+        }
+        self.map._waiters.wake_all();
+    }
+}
+
+// Threads parked in get_or_insert_with until a value another thread computes is there. That
+// thread wakes them all once it is done, each one then checks on its own key.
+#[derive(Debug)]
+struct Waiters {
+    count: AtomicUsize,
+    lock: Mutex<()>,
+    wakeup: Condvar,
+}
+
+impl Waiters {
+    fn new() -> Waiters {
+        Waiters {
+            count: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    // Parks the current thread for as long as pending() holds.
+    fn wait_while<F: Fn() -> bool>(&self, pending: F) {
+        // Registered first, a value completed meanwhile is either seen by pending() or wakes
+        // this thread up
+        self.count.fetch_add(1, MEMORY_ORDERING);
+        let mut lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while pending() {
+            lock = self
+                .wakeup
+                .wait(lock)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(lock);
+        self.count.fetch_sub(1, MEMORY_ORDERING);
+    }
+
+    fn wake_all(&self) {
+        if self.count.load(MEMORY_ORDERING) > 0 {
+            // Once the lock is ours, a waiter that still saw the value pending is asleep
+            drop(self.lock.lock().unwrap_or_else(PoisonError::into_inner));
+            self.wakeup.notify_all();
+        }
+    }
+}
+
+// debuging functions
+#[allow(dead_code)]
+unsafe fn print_table<K: Eq + Hash + ToString, V: Eq + ToString, S>(
+    table: &NonBlockingHashMap<K, V, S>,
+) {
+    print_kvs(table._kvs.load(MEMORY_ORDERING));
+}
+
+pub fn print_all<K: Eq + Hash + ToString, V: Eq + ToString, S>(
+    table: &NonBlockingHashMap<K, V, S>,
+) {
+    let _guard = pin();
+    let mut kvs = table._kvs.load(MEMORY_ORDERING);
+    let mut i = 0;
+    while !kvs.is_null() {
+        println!("---Table {}---", i);
+        unsafe { print_kvs(kvs) };
+        i += 1;
+        kvs = unsafe { (*kvs)._chm.get_newkvs_nonatomic() };
+    }
+}
+
+unsafe fn print_kvs<K: Eq + Hash + ToString, V: Eq + ToString>(kvs: *mut KVs<K, V>) {
+    for i in 0..(*kvs).len() {
+        print!(
+            "{}: ({}, ",
+            i,
+            key_to_string((*kvs).get_key_nonatomic_at(i))
+        );
+        print!("{}, ", value_to_string((*kvs).get_value_nonatomic_at(i)));
+        println!("{})", (*kvs).get_hash(i));
+    }
+}
+
+unsafe fn key_to_string<K: Eq + Hash + ToString>(key: *mut Key<K>) -> String {
+    match Key::keytype(key) {
+        KeyTombStone => String::from("TOMBSTONE"),
+        KeyEmpty => String::from("EMPTY"),
+        KeyType => (*key).get().to_string(),
+    }
+}
+
+unsafe fn value_to_string<V: Eq + ToString>(value: *mut Value<V>) -> String {
+    match Value::valuetype(value) {
+        ValueTombStone => {
+            if Value::is_prime(value) {
+                String::from("TOMBPRIME")
+            } else {
+                String::from("TOMBSTONE")
+            }
+        }
+        ValueEmpty => String::from("EMPTY"),
+        ValuePending => {
+            if Value::is_prime(value) {
+                String::from("Prime(PENDING)")
+            } else {
+                String::from("PENDING")
             }
         }
-        ValueEmpty => String::from("EMPTY"),
         ValueType => {
-            assert!(!(*value)._value.is_null());
-            let val_str = (*(*value)._value).to_string();
-            if (*value).is_prime() {
+            let val_str = Value::get_value(value).unwrap().to_string();
+            if Value::is_prime(value) {
                 format!("Prime({})", val_str)
             } else {
                 val_str
@@ -688,10 +1602,16 @@ unsafe fn value_to_string<V: Eq + ToString>(value: *mut Value<V>) -> String {
 #[cfg(test)]
 mod test {
     use super::{
-        ConcurrentMap, KVs, Key, KeyEmpty, NonBlockingHashMap, Value, ValueEmpty, MEMORY_ORDERING,
+        pin, ConcurrentMap, Entry, KVs, Key, KeyEmpty, KeyTombStone, NonBlockingHashMap, Value,
+        ValueEmpty, MEMORY_ORDERING,
     };
-    use std::sync::atomic::AtomicPtr;
-    use std::sync::Arc;
+    use std::collections::HashMap;
+    use std::hash::{BuildHasherDefault, Hash, Hasher};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Barrier};
     use std::thread::{sleep, spawn};
     use std::time::Duration;
 
@@ -700,11 +1620,13 @@ mod test {
         unsafe {
             let value: *mut Value<i32> = Box::into_raw(Box::new(Value::new(10)));
             let atomicvalue = AtomicPtr::new(value);
-            let valueprime = (*value).get_prime();
-            assert!(!(*atomicvalue.load(MEMORY_ORDERING)).is_prime());
+            let valueprime = Value::prime(value);
+            assert!(!Value::is_prime(atomicvalue.load(MEMORY_ORDERING)));
             atomicvalue.swap(valueprime, MEMORY_ORDERING);
-            assert!((*atomicvalue.load(MEMORY_ORDERING))._value == (*value)._value);
-            assert!((*atomicvalue.load(MEMORY_ORDERING)).is_prime());
+            assert!(Value::unprime(atomicvalue.load(MEMORY_ORDERING)) == value);
+            assert!(Value::is_prime(atomicvalue.load(MEMORY_ORDERING)));
+            assert_eq!(Value::get_value(valueprime), Some(&10));
+            Value::free(valueprime);
         }
     }
 
@@ -713,53 +1635,57 @@ mod test {
         unsafe {
             {
                 let kv = Key::new(10);
-                let p = kv.get_key();
-                assert!((*p) == 10);
+                assert!(*kv.get() == 10);
             }
 
             {
-                let kv = Value::new(10);
-                let p = kv.get_value();
-                assert!((*p) == 10);
+                let kv = Box::into_raw(Box::new(Value::new(10)));
+                assert_eq!(Value::get_value(kv), Some(&10));
+                Value::free(kv);
             }
         }
     }
 
     #[test]
     fn test_key_eq() {
-        assert!(Key::<i32>::new_empty() == Key::<i32>::new_empty());
-        assert!(Key::<i32>::new_tombstone() == Key::<i32>::new_tombstone());
         assert!(Key::<i32>::new(10) == Key::<i32>::new(10));
         assert!(Key::<i32>::new(5) != Key::<i32>::new(10));
+        assert!(Key::<i32>::keytype(Key::empty()) == KeyEmpty);
+        assert!(Key::<i32>::keytype(Key::tombstone()) == KeyTombStone);
     }
 
     #[test]
     fn test_value_eq() {
         unsafe {
-            assert!(Value::<i32>::new_empty() == Value::<i32>::new_empty());
-            assert!(Value::<i32>::new_tombstone() == Value::<i32>::new_tombstone());
-            assert!(
-                (*Value::<i32>::new_tombstone().get_prime())
-                    == (*Value::<i32>::new_tombstone().get_prime())
-            );
-            assert!(Value::<i32>::new_tombprime() == (*Value::<i32>::new_tombstone().get_prime()));
-            assert!(Value::<i32>::new_tombprime() == Value::<i32>::new_tombprime());
-            assert!(Value::<i32>::new(10) == Value::<i32>::new(10));
-            assert!(Value::<i32>::new(5) != Value::<i32>::new(10));
-            assert!((*Value::<i32>::new(10).get_prime()) == (*Value::<i32>::new(10).get_prime()));
+            let ten = Box::into_raw(Box::new(Value::<i32>::new(10)));
+            let other_ten = Box::into_raw(Box::new(Value::<i32>::new(10)));
+            let five = Box::into_raw(Box::new(Value::<i32>::new(5)));
+            assert!(Value::same(Value::<i32>::empty(), Value::empty()));
+            assert!(Value::same(Value::<i32>::tombstone(), Value::tombstone()));
+            assert!(Value::same(
+                Value::<i32>::tombprime(),
+                Value::prime(Value::tombstone())
+            ));
+            assert!(!Value::same(Value::<i32>::tombstone(), Value::tombprime()));
+            assert!(!Value::same(Value::<i32>::empty(), Value::tombstone()));
+            assert!(Value::same(ten, other_ten));
+            assert!(!Value::same(five, ten));
+            assert!(!Value::same(ten, Value::prime(other_ten)));
+            assert!(Value::same(Value::prime(ten), Value::prime(other_ten)));
+            assert!(!Value::same(ten, Value::tombstone()));
+            Value::free(ten);
+            Value::free(other_ten);
+            Value::free(five);
         }
     }
 
     #[test]
     fn test_kvs_init() {
         let kvs = KVs::<i32, i32>::new(10);
-        unsafe {
-            for i in 0..kvs._ks.len() {
-                assert_eq!((*kvs._ks[i].load(MEMORY_ORDERING)).keytype(), KeyEmpty);
-            }
-            for i in 0..kvs._ks.len() {
-                assert_eq!((*kvs._vs[i].load(MEMORY_ORDERING)).valuetype(), ValueEmpty);
-            }
+        for i in 0..kvs.len() {
+            assert_eq!(Key::keytype(kvs.get_key_nonatomic_at(i)), KeyEmpty);
+            assert_eq!(Value::valuetype(kvs.get_value_nonatomic_at(i)), ValueEmpty);
+            assert_eq!(kvs.get_hash(i), 0);
         }
     }
 
@@ -810,11 +1736,1234 @@ mod test {
     fn test_hashmap_single_thread_grow() {
         let map = ConcurrentMap::new_with_size(10);
         for n in 0..200_000 {
-            map.as_mut().put(n, n);
+            map.insert_cloned(n, n);
         }
+        let guard = pin();
         for n in 0..200_000 {
-            assert_eq!(n, *map.as_mut().get(n).unwrap());
+            assert_eq!(n, *map.get(&n, &guard).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_hashmap_shared_ref() {
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..1000 {
+                        map.insert_cloned(t * 1000 + i, i);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = pin();
+        for n in 0..4000 {
+            assert_eq!(n % 1000, *map.get(&n, &guard).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_hashmap_get_borrowed() {
+        let map = NonBlockingHashMap::<String, i32>::new_with_size(10);
+        let guard = pin();
+        map.insert_cloned(String::from("one"), 1);
+        map.insert_cloned(String::from("two"), 2);
+        assert_eq!(map.get("one", &guard), Some(&1));
+        assert_eq!(map.get(&String::from("two"), &guard), Some(&2));
+        assert_eq!(map.get("three", &guard), None);
+    }
+
+    #[test]
+    fn test_hashmap_guard_get_cloned() {
+        let map = NonBlockingHashMap::<i32, String>::new_with_size(10);
+        map.insert_cloned(1, String::from("a"));
+        let guard = map.guard();
+        let a = map.get(&1, &guard).unwrap();
+        map.insert_cloned(1, String::from("b"));
+        // The replaced value is still readable while the guard is held
+        assert_eq!(a, "a");
+        drop(guard);
+        assert_eq!(map.get_cloned(&1), Some(String::from("b")));
+        assert_eq!(map.get_cloned(&2), None);
+        assert_eq!(map.get(&1, &map.pin()), Some(&String::from("b")));
+    }
+
+    // Hashes integer keys to themselves
+    #[derive(Default)]
+    struct IdentityHasher(u64);
+
+    impl Hasher for IdentityHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for b in bytes {
+                self.0 = (self.0 << 8) | u64::from(*b);
+            }
+        }
+
+        fn write_u64(&mut self, n: u64) {
+            self.0 = n;
+        }
+    }
+
+    #[test]
+    fn test_hashmap_with_hasher() {
+        let map = ConcurrentMap::<u64, u64, _>::with_capacity_and_hasher(
+            10,
+            BuildHasherDefault::<IdentityHasher>::default(),
+        );
+        for n in 0..10_000 {
+            map.insert_cloned(n, n * 2);
+        }
+        for n in 0..10_000 {
+            assert_eq!(map.get_cloned(&n), Some(n * 2));
+        }
+        assert_eq!(map.len(), 10_000);
+        let map = NonBlockingHashMap::<u64, u64, BuildHasherDefault<IdentityHasher>>::default();
+        map.insert_cloned(1, 1);
+        assert_eq!(map.get_cloned(&1), Some(1));
+    }
+
+    #[test]
+    fn test_hashmap_colliding_keys() {
+        let map = NonBlockingHashMap::<u64, u64, _>::with_capacity_and_hasher(
+            10,
+            BuildHasherDefault::<IdentityHasher>::default(),
+        );
+        // Every key lands in slot 0, a few more than REPROBE_LIMIT
+        let len = map.capacity() as u64;
+        for n in 0..15 {
+            map.insert_cloned(n * len, n);
+        }
+        for n in 0..15 {
+            assert_eq!(map.get_cloned(&(n * len)), Some(n));
+        }
+    }
+
+    static KEY_COMPARISONS: AtomicUsize = AtomicUsize::new(0);
+
+    // Counts how often keys are compared
+    struct ComparedKey(u64);
+
+    impl Hash for ComparedKey {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.hash(state);
+        }
+    }
+
+    impl PartialEq for ComparedKey {
+        fn eq(&self, other: &ComparedKey) -> bool {
+            KEY_COMPARISONS.fetch_add(1, MEMORY_ORDERING);
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for ComparedKey {}
+
+    #[test]
+    fn test_hashmap_probe_compares_hashes_first() {
+        let map = NonBlockingHashMap::<ComparedKey, u64, _>::with_capacity_and_hasher(
+            10,
+            BuildHasherDefault::<IdentityHasher>::default(),
+        );
+        // Same slot, different hashes
+        let len = map.capacity() as u64;
+        for n in 1..4 {
+            map.insert_cloned(ComparedKey(n * len), n);
+        }
+        KEY_COMPARISONS.store(0, MEMORY_ORDERING);
+        assert_eq!(map.get_cloned(&ComparedKey(3 * len)), Some(3));
+        assert_eq!(map.insert_cloned(ComparedKey(3 * len), 4), Some(3));
+        assert_eq!(KEY_COMPARISONS.load(MEMORY_ORDERING), 2);
+    }
+
+    #[test]
+    fn test_hashmap_len_contains_key() {
+        let map = NonBlockingHashMap::<String, usize>::new_with_size(10);
+        assert!(map.is_empty());
+        for n in 0..1000 {
+            map.insert_cloned(n.to_string(), n);
+        }
+        assert_eq!(map.len(), 1000);
+        assert!(!map.is_empty());
+        for n in (0..1000).step_by(2) {
+            map.remove_cloned(&n.to_string());
+        }
+        map.insert_cloned(String::from("1"), 0);
+        assert_eq!(map.len(), 500);
+        assert!(map.contains_key("1"));
+        assert!(!map.contains_key("2"));
+        assert!(!map.contains_key("1000"));
+    }
+
+    #[test]
+    fn test_hashmap_remove() {
+        let map = NonBlockingHashMap::<i32, String>::new_with_size(10);
+        let guard = pin();
+        for n in 0..100 {
+            map.insert_cloned(n, n.to_string());
+        }
+        for n in (0..100).step_by(2) {
+            assert_eq!(map.remove_cloned(&n), Some(n.to_string()));
+        }
+        assert_eq!(map.remove_cloned(&0), None);
+        assert_eq!(map.remove_cloned(&1000), None);
+        assert_eq!(map.remove_entry_cloned(&1), Some((1, String::from("1"))));
+        for n in 0..100 {
+            assert_eq!(map.get(&n, &guard).is_some(), n % 2 == 1 && n != 1);
+        }
+        assert_eq!(map.len(), 49);
+        map.insert_cloned(0, String::from("again"));
+        assert_eq!(map.get(&0, &guard), Some(&String::from("again")));
+    }
+
+    // Equal whatever its tag, so the tag tells which of two equal keys is in the map
+    #[derive(Clone, Debug)]
+    struct TaggedKey(u32, &'static str);
+
+    impl PartialEq for TaggedKey {
+        fn eq(&self, other: &TaggedKey) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for TaggedKey {}
+
+    impl Hash for TaggedKey {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.hash(state);
+        }
+    }
+
+    // Equal by its first field alone, like a record compared by id; the second one changes
+    // without the comparison seeing it
+    #[derive(Clone, Debug)]
+    struct Record(usize, usize);
+
+    impl PartialEq for Record {
+        fn eq(&self, other: &Record) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Record {}
+
+    #[test]
+    fn test_hashmap_insert_remove_not_clone() {
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(10);
+        let drops = Arc::new(AtomicUsize::new(0));
+        let guard = pin();
+        assert!(map.insert(1, Counted(1, drops.clone()), &guard).is_none());
+        assert_eq!(
+            map.insert(1, Counted(2, drops.clone()), &guard).unwrap().0,
+            1
+        );
+        let (key, value) = map.remove_entry(&1, &guard).unwrap();
+        assert_eq!((*key, value.0), (1, 2));
+        assert!(map.remove(&1, &guard).is_none());
+        assert!(map.replace(2, Counted(3, drops.clone()), &guard).is_none());
+        map.insert(2, Counted(4, drops.clone()), &guard);
+        assert_eq!(
+            map.replace(2, Counted(5, drops.clone()), &guard).unwrap().0,
+            4
+        );
+        assert_eq!(map.remove(&2, &guard).unwrap().0, 5);
+        drop(guard);
+        wait_for_drops(&drops, 5);
+    }
+
+    #[test]
+    fn test_hashmap_remove_borrowed() {
+        let map = NonBlockingHashMap::<String, usize>::new_with_size(10);
+        map.insert_cloned(String::from("a"), 1);
+        map.insert_cloned(String::from("b"), 2);
+        assert_eq!(map.remove_cloned("a"), Some(1));
+        assert_eq!(map.remove_cloned("a"), None);
+        assert_eq!(map.remove_entry_cloned("b"), Some((String::from("b"), 2)));
+        assert!(map.is_empty());
+
+        let map = NonBlockingHashMap::<TaggedKey, usize>::new_with_size(10);
+        map.insert_cloned(TaggedKey(1, "stored"), 1);
+        let (key, value) = map.remove_entry_cloned(&TaggedKey(1, "probe")).unwrap();
+        assert_eq!((key.1, value), ("stored", 1));
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_remove_grow() {
+        let nthreads = 8;
+        let num_keys = 10_000;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let guard = pin();
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in t * num_keys..(t + 1) * num_keys {
+                        map.insert_cloned(i, i);
+                        if i % 3 == 0 {
+                            assert_eq!(map.remove_cloned(&i), Some(i));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        for i in 0..nthreads * num_keys {
+            assert_eq!(map.get(&i, &guard).is_some(), i % 3 != 0);
+        }
+        let expected = (0..nthreads * num_keys).filter(|i| i % 3 != 0).count();
+        assert_eq!(map.len(), expected);
+    }
+
+    #[test]
+    fn test_hashmap_put_if_absent() {
+        let map = NonBlockingHashMap::<i32, i32>::new_with_size(10);
+        let guard = pin();
+        assert_eq!(map.put_if_absent(1, 10, &guard), Ok(()));
+        assert_eq!(map.put_if_absent(1, 20, &guard), Err(&10));
+        assert_eq!(map.remove_cloned(&1), Some(10));
+        assert_eq!(map.put_if_absent(1, 30, &guard), Ok(()));
+        assert_eq!(map.get(&1, &guard), Some(&30));
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_put_if_absent() {
+        let nthreads = 8;
+        let num_keys = 10_000;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let wins = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                let wins = wins.clone();
+                spawn(move || {
+                    for i in 0..num_keys {
+                        match map.put_if_absent(i, t, &pin()) {
+                            Ok(()) => {
+                                wins.fetch_add(1, MEMORY_ORDERING);
+                            }
+                            Err(v) => assert!(*v < nthreads),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = pin();
+        assert_eq!(wins.load(MEMORY_ORDERING), num_keys);
+        for i in 0..num_keys {
+            assert!(*map.get(&i, &guard).unwrap() < nthreads);
+        }
+    }
+
+    #[test]
+    fn test_hashmap_replace() {
+        let map = NonBlockingHashMap::<i32, i32>::new_with_size(10);
+        let guard = pin();
+        assert_eq!(map.replace_cloned(1, 10), None);
+        assert_eq!(map.get(&1, &guard), None);
+        map.insert_cloned(1, 10);
+        assert_eq!(map.replace_cloned(1, 20), Some(10));
+        assert_eq!(map.get(&1, &guard), Some(&20));
+        map.remove_cloned(&1);
+        assert_eq!(map.replace_cloned(1, 30), None);
+        assert_eq!(map.get(&1, &guard), None);
+    }
+
+    #[test]
+    fn test_hashmap_compare_and_set() {
+        let map = NonBlockingHashMap::<i32, String>::new_with_size(10);
+        let guard = pin();
+        assert_eq!(
+            map.compare_and_set(1, &String::from("a"), String::from("b"), &guard),
+            Err(None)
+        );
+        assert_eq!(map.get(&1, &guard), None);
+        map.insert_cloned(1, String::from("a"));
+        assert_eq!(
+            map.compare_and_set(1, &String::from("x"), String::from("b"), &guard),
+            Err(Some(&String::from("a")))
+        );
+        assert_eq!(
+            map.compare_and_set(1, &String::from("a"), String::from("b"), &guard),
+            Ok(())
+        );
+        assert_eq!(map.get(&1, &guard), Some(&String::from("b")));
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_compare_and_set() {
+        let nthreads = 8;
+        let num_keys = 1000;
+        let rounds = 100;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        for i in 0..num_keys {
+            map.insert_cloned(i, 0);
+        }
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for _ in 0..rounds {
+                        for i in 0..num_keys {
+                            let guard = pin();
+                            let mut cur = *map.get(&i, &guard).unwrap();
+                            while let Err(v) = map.compare_and_set(i, &cur, cur + 1, &guard) {
+                                cur = *v.unwrap();
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = pin();
+        for i in 0..num_keys {
+            assert_eq!(*map.get(&i, &guard).unwrap(), nthreads * rounds);
+        }
+    }
+
+    #[test]
+    fn test_hashmap_put_returns_previous() {
+        let map = NonBlockingHashMap::<i32, String>::new_with_size(10);
+        assert_eq!(map.insert_cloned(1, String::from("a")), None);
+        assert_eq!(
+            map.insert_cloned(1, String::from("b")),
+            Some(String::from("a"))
+        );
+        assert_eq!(
+            map.insert_cloned(1, String::from("b")),
+            Some(String::from("b"))
+        );
+        map.remove_cloned(&1);
+        assert_eq!(map.insert_cloned(1, String::from("c")), None);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_put_returns_previous() {
+        let nthreads = 8;
+        let num_keys = 10_000;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let inserted = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                let inserted = inserted.clone();
+                spawn(move || {
+                    for i in 0..num_keys {
+                        if map.insert_cloned(i, t).is_none() {
+                            inserted.fetch_add(1, MEMORY_ORDERING);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert_eq!(inserted.load(MEMORY_ORDERING), num_keys);
+    }
+
+    // Counts how many values have been dropped
+    struct Counted(usize, Arc<AtomicUsize>);
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Counted) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Counted {}
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, MEMORY_ORDERING);
+        }
+    }
+
+    #[test]
+    fn test_hashmap_reclaims_replaced_values() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(16);
+        map.put_if_absent(0, Counted(0, drops.clone()), &pin())
+            .ok()
+            .unwrap();
+        for n in 1..1000 {
+            let guard = pin();
+            let cur = map.get(&0, &guard).unwrap();
+            assert!(map
+                .compare_and_set(0, cur, Counted(n, drops.clone()), &guard)
+                .is_ok());
+        }
+        wait_for_drops(&drops, 999);
+    }
+
+    #[test]
+    fn test_hashmap_identity_updates_keep_values() {
+        // Putting a value equal to the current one must still install it: the caller gets a
+        // reference to it, so it cannot be dropped while the guard is pinned
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(16);
+        let guard = pin();
+        map.insert(0, Counted(0, drops.clone()), &guard);
+        let same = |v: &Counted| Counted(v.0, v.1.clone());
+        let v = map.update(0, |v| v.map(same), &guard).unwrap();
+        assert!(ptr::eq(v, map.get(&0, &guard).unwrap()));
+        match map.entry(0, &guard).and_modify(same) {
+            Entry::Occupied(entry) => assert!(ptr::eq(entry.get(), map.get(&0, &guard).unwrap())),
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(drops.load(MEMORY_ORDERING), 0);
+        let v = map.merge(0, Counted(1, drops.clone()), |v, _| same(v), &guard);
+        assert!(ptr::eq(v, map.get(&0, &guard).unwrap()));
+        assert_eq!(v.0, 0);
+        // Only the value merge did not need
+        assert_eq!(drops.load(MEMORY_ORDERING), 1);
+        drop(guard);
+        // Plus the values replaced by update, and_modify and merge
+        wait_for_drops(&drops, 4);
+    }
+
+    #[test]
+    fn test_hashmap_into_iter_while_pinned() {
+        // The tables retired by the resizes still share every entry and cannot be freed while
+        // the guard is pinned
+        let drops = Arc::new(AtomicUsize::new(0));
+        let guard = pin();
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(16);
+        for n in 0..1000 {
+            map.insert(n, Counted(n, drops.clone()), &guard);
+        }
+        let mut entries: Vec<_> = map.into_iter().collect();
+        entries.sort_by_key(|(k, _)| *k);
+        assert_eq!(entries.len(), 1000);
+        assert!(entries
+            .iter()
+            .enumerate()
+            .all(|(n, (k, v))| n == *k && n == v.0));
+        assert_eq!(drops.load(MEMORY_ORDERING), 0);
+        drop(entries);
+        assert_eq!(drops.load(MEMORY_ORDERING), 1000);
+        // Freeing the retired tables drops nothing more
+        drop(guard);
+        for _ in 0..100 {
+            pin().flush();
+        }
+        assert_eq!(drops.load(MEMORY_ORDERING), 1000);
+    }
+
+    #[test]
+    fn test_hashmap_into_iter_drops_the_rest() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(16);
+        for n in 0..1000 {
+            map.put_if_absent(n, Counted(n, drops.clone()), &pin())
+                .ok()
+                .unwrap();
+        }
+        let mut entries = map.into_iter();
+        for (k, v) in entries.by_ref().take(10) {
+            assert_eq!(k, v.0);
+        }
+        assert_eq!(drops.load(MEMORY_ORDERING), 10);
+        drop(entries);
+        wait_for_drops(&drops, 1000);
+    }
+
+    #[test]
+    fn test_hashmap_clear() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(16);
+        for n in 0..1000 {
+            map.put_if_absent(n, Counted(n, drops.clone()), &pin())
+                .ok()
+                .unwrap();
+        }
+        // Leave a resize in progress, the whole chain goes
+        unsafe { map.resize(map.get_table_nonatomic()) };
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert!(!map.contains_key(&0));
+        assert_eq!(map.iter(&pin()).count(), 0);
+        wait_for_drops(&drops, 1000);
+        map.put_if_absent(0, Counted(0, drops.clone()), &pin())
+            .ok()
+            .unwrap();
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key(&0));
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_clear() {
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..20_000 {
+                        let key = i % 500;
+                        if t % 2 == 0 {
+                            map.insert_cloned(key, key * 2);
+                        } else if let Some(v) = map.get(&key, &pin()) {
+                            assert_eq!(*v, key * 2);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..100 {
+            map.clear();
+        }
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.iter(&pin()).count(), 0);
+    }
+
+    #[test]
+    fn test_hashmap_retain() {
+        let map = NonBlockingHashMap::<usize, usize>::new_with_size(16);
+        for n in 0..1000 {
+            map.insert_cloned(n, n);
+        }
+        map.retain(|k, v| {
+            assert_eq!(k, v);
+            k % 3 == 0
+        });
+        assert_eq!(map.len(), 334);
+        assert!((0..1000).all(|n| map.contains_key(&n) == (n % 3 == 0)));
+    }
+
+    #[test]
+    fn test_hashmap_retain_keeps_replaced_values() {
+        let map = NonBlockingHashMap::<usize, usize>::new_with_size(16);
+        for n in 0..100 {
+            map.insert_cloned(n, n);
+        }
+        // Replace every value while retain is looking at it
+        map.retain(|k, _| {
+            map.insert_cloned(*k, k + 1);
+            false
+        });
+        assert_eq!(map.len(), 100);
+        assert!((0..100).all(|n| map.get_cloned(&n) == Some(n + 1)));
+    }
+
+    #[test]
+    fn test_hashmap_retain_keeps_equal_replacements() {
+        let map = NonBlockingHashMap::<usize, Record>::new_with_size(16);
+        for n in 0..100 {
+            map.insert_cloned(n, Record(n, 0));
+        }
+        // Refresh every expired record while retain is looking at it
+        map.retain(|k, v| {
+            map.insert_cloned(*k, Record(*k, 1));
+            v.1 == 1
+        });
+        assert_eq!(map.len(), 100);
+        assert!((0..100).all(|n| map.get_cloned(&n).unwrap().1 == 1));
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_retain() {
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        for n in 0..1000 {
+            map.insert_cloned(n, 0);
+        }
+        // Writers keep bumping odd keys; retain must never drop a bumped value
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 1..2000 {
+                        map.insert_cloned((i % 1000) | 1, 1);
+                    }
+                })
+            })
+            .collect();
+        map.retain(|_, v| *v != 0);
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert!((0..1000).all(|n| map.get_cloned(&n) == (n % 2 == 1).then_some(1)));
+    }
+
+    #[test]
+    fn test_hashmap_drain() {
+        let map = NonBlockingHashMap::<String, usize>::new_with_size(16);
+        for n in 0..1000 {
+            map.insert_cloned(n.to_string(), n);
+        }
+        let guard = pin();
+        let mut drained: Vec<_> = map.drain(&guard).collect();
+        assert!(map.is_empty());
+        drained.sort_by_key(|(_, v)| *v);
+        let expected: Vec<_> = (0..1000).map(|n| (n.to_string(), n)).collect();
+        assert_eq!(drained, expected);
+        for n in 0..1000 {
+            map.insert_cloned(n.to_string(), n);
+        }
+        // Dropping the drain removes what it did not yield
+        assert_eq!(map.drain(&guard).take(10).count(), 10);
+        assert!(map.is_empty());
+        map.insert_cloned(String::from("0"), 0);
+        assert_eq!(map.take_all(), vec![(String::from("0"), 0)]);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_drain() {
+        // Writers count into a map that is flushed while they run; no count may be lost
+        let nthreads = 4;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..20_000 {
+                        map.insert_cloned(t * 20_000 + i, 1);
+                    }
+                })
+            })
+            .collect();
+        let mut total = 0;
+        for _ in 0..10 {
+            total += map.take_all().len();
+        }
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        total += map.take_all().len();
+        assert_eq!(total, nthreads * 20_000);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_hashmap_from_iter_extend() {
+        let mut map: NonBlockingHashMap<usize, usize> = (0..1000).map(|n| (n, n)).collect();
+        // Presized: no resize needed
+        assert!(!unsafe { (*map.get_table_nonatomic())._chm.has_newkvs() });
+        assert_eq!(map.capacity(), 4096);
+        assert_eq!(map.len(), 1000);
+        map.extend((500..1500).map(|n| (n, n + 1)));
+        assert_eq!(map.len(), 1500);
+        assert!((0..1500).all(|n| map.get_cloned(&n) == Some(if n < 500 { n } else { n + 1 })));
+        // Extending grows the table up front, from the size hint
+        let mut map = NonBlockingHashMap::<usize, usize>::new_with_size(16);
+        map.insert_cloned(0, 0);
+        map.reserve(999);
+        assert!(!unsafe { (*map.get_table_nonatomic())._chm.has_newkvs() });
+        assert_eq!(map.capacity(), 4096);
+        map.reserve(10);
+        assert_eq!(map.capacity(), 4096);
+        map.extend((1..1000).map(|n| (n, n)));
+        assert_eq!(map.capacity(), 4096);
+        assert!((0..1000).all(|n| map.get_cloned(&n) == Some(n)));
+
+        let std_map: HashMap<String, usize> = (0..100).map(|n| (n.to_string(), n)).collect();
+        let map = NonBlockingHashMap::from(std_map.clone());
+        assert_eq!(map.len(), 100);
+        assert!(std_map
+            .iter()
+            .all(|(k, v)| map.get_cloned(k.as_str()) == Some(*v)));
+    }
+
+    #[test]
+    fn test_hashmap_entry() {
+        let map = NonBlockingHashMap::<String, usize>::new_with_size(16);
+        let guard = pin();
+        assert!(matches!(
+            map.entry(String::from("a"), &guard),
+            Entry::Vacant(_)
+        ));
+        assert_eq!(*map.entry(String::from("a"), &guard).or_insert(1), 1);
+        assert_eq!(*map.entry(String::from("a"), &guard).or_insert(2), 1);
+        assert_eq!(*map.entry(String::from("b"), &guard).or_default(), 0);
+        let entry = map.entry(String::from("a"), &guard).and_modify(|v| v + 10);
+        assert_eq!(entry.key(), "a");
+        assert!(matches!(entry, Entry::Occupied(ref e) if *e.get() == 11));
+        assert_eq!(map.get_cloned("a"), Some(11));
+        // Nothing to modify in a vacant entry
+        let entry = map.entry(String::from("c"), &guard).and_modify(|v| v + 10);
+        assert_eq!(*entry.or_insert_with(|| 5), 5);
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_entry() {
+        let nthreads = 8;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let winners = Arc::new(AtomicUsize::new(0));
+        for key in 1000..1010 {
+            map.insert_cloned(key, 0);
+        }
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                let winners = winners.clone();
+                spawn(move || {
+                    for key in 0..1000 {
+                        let guard = pin();
+                        // Every racer sees the same winner
+                        let first = map.entry(key, &guard).or_insert_with(|| t);
+                        if *first == t {
+                            winners.fetch_add(1, MEMORY_ORDERING);
+                        }
+                        assert_eq!(map.get(&key, &guard), Some(first));
+                        // Concurrent modifications are never lost
+                        map.entry(1000 + key % 10, &guard).and_modify(|v| v + 1);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert_eq!(winners.load(MEMORY_ORDERING), 1000);
+        let guard = pin();
+        let counts: usize = (1000..1010).map(|k| *map.get(&k, &guard).unwrap()).sum();
+        assert_eq!(counts, nthreads * 1000);
+    }
+
+    #[test]
+    fn test_hashmap_and_modify_retries_on_equal_replacement() {
+        let map = NonBlockingHashMap::<usize, Record>::new_with_size(16);
+        let guard = pin();
+        map.insert_cloned(0, Record(0, 0));
+        let mut calls = 0;
+        let bump = |v: &Record| {
+            calls += 1;
+            if calls == 1 {
+                // Another writer puts an equal record while f is running
+                map.insert_cloned(0, Record(0, 10));
+            }
+            Record(v.0, v.1 + 1)
+        };
+        match map.entry(0, &guard).and_modify(bump) {
+            Entry::Occupied(entry) => assert_eq!(entry.get().1, 11),
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(calls, 2);
+        assert_eq!(map.get_cloned(&0).unwrap().1, 11);
+    }
+
+    #[test]
+    fn test_hashmap_update() {
+        let map = NonBlockingHashMap::<String, Vec<usize>>::new_with_size(16);
+        let guard = pin();
+        assert_eq!(map.update(String::from("a"), |_| None, &guard), None);
+        let append = |v: Option<&Vec<usize>>| {
+            let mut v = v.cloned().unwrap_or_default();
+            v.push(v.len());
+            Some(v)
+        };
+        assert_eq!(
+            map.update(String::from("a"), append, &guard),
+            Some(&vec![0])
+        );
+        assert_eq!(
+            map.update(String::from("a"), append, &guard),
+            Some(&vec![0, 1])
+        );
+        assert_eq!(map.update(String::from("a"), |_| None, &guard), None);
+        assert!(!map.contains_key("a"));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_hashmap_update_retries_on_equal_replacement() {
+        let map = NonBlockingHashMap::<usize, Record>::new_with_size(16);
+        let guard = pin();
+        map.insert_cloned(0, Record(0, 0));
+        let mut calls = 0;
+        let bump = |v: Option<&Record>| {
+            calls += 1;
+            if calls == 1 {
+                // Another writer puts an equal record while f is running
+                map.insert_cloned(0, Record(0, 10));
+            }
+            v.map(|v| Record(v.0, v.1 + 1))
+        };
+        assert_eq!(map.update(0, bump, &guard).unwrap().1, 11);
+        assert_eq!(calls, 2);
+        assert_eq!(map.get_cloned(&0).unwrap().1, 11);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_update() {
+        let nthreads = 8;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..10_000 {
+                        let increment = |v: Option<&usize>| Some(v.map_or(1, |v| v + 1));
+                        map.update(i % 10, increment, &pin());
+                        // Bounce a key in and out of the map
+                        let toggle = |v: Option<&usize>| if v.is_some() { None } else { Some(1) };
+                        map.update(100, toggle, &pin());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = pin();
+        let counts: usize = (0..10).map(|k| *map.get(&k, &guard).unwrap()).sum();
+        assert_eq!(counts, nthreads * 10_000);
+        // An even number of toggles
+        assert!(!map.contains_key(&100));
+    }
+
+    #[test]
+    fn test_hashmap_merge() {
+        let map = NonBlockingHashMap::<&str, String>::new_with_size(16);
+        let guard = pin();
+        let concat = |old: &String, new: &String| format!("{}{}", old, new);
+        assert_eq!(map.merge("a", String::from("x"), concat, &guard), "x");
+        assert_eq!(map.merge("a", String::from("y"), concat, &guard), "xy");
+        assert_eq!(map.merge("b", String::from("z"), concat, &guard), "z");
+        assert_eq!(map.get_cloned("a"), Some(String::from("xy")));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_hashmap_merge_keeps_equal_replacements() {
+        // Records are equal by id alone, a merge must still see the count another one added
+        let map = NonBlockingHashMap::<usize, Record>::new_with_size(16);
+        let guard = pin();
+        let add = |old: &Record, new: &Record| Record(old.0, old.1 + new.1);
+        map.merge(0, Record(0, 1), add, &guard);
+        let mut calls = 0;
+        let racing = |old: &Record, new: &Record| {
+            calls += 1;
+            if calls == 1 {
+                map.merge(0, Record(0, 10), add, &pin());
+            }
+            add(old, new)
+        };
+        assert_eq!(map.merge(0, Record(0, 100), racing, &guard).1, 111);
+        assert_eq!(calls, 2);
+        assert_eq!(map.get_cloned(&0).unwrap().1, 111);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_merge() {
+        let nthreads = 8;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..10_000 {
+                        map.merge(i % 10, 1, |old, new| old + new, &pin());
+                        // Keys that keep being removed make merges insert as well as combine
+                        map.merge(100, 1, |old, new| old + new, &pin());
+                        if i % 100 == 0 {
+                            map.remove_cloned(&100);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
         }
+        let guard = pin();
+        let counts: usize = (0..10).map(|k| *map.get(&k, &guard).unwrap()).sum();
+        assert_eq!(counts, nthreads * 10_000);
+    }
+
+    #[test]
+    fn test_hashmap_get_or_insert_with_runs_once() {
+        let nthreads = 8;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(nthreads));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                spawn(move || {
+                    for key in 0..200 {
+                        barrier.wait();
+                        let mut guard = pin();
+                        let value = map.get_or_insert_with(
+                            key,
+                            || {
+                                calls.fetch_add(1, MEMORY_ORDERING);
+                                sleep(Duration::from_micros(100));
+                                key * nthreads + t
+                            },
+                            &mut guard,
+                        );
+                        assert_eq!(*value / nthreads, key);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert_eq!(calls.load(MEMORY_ORDERING), 200);
+        assert_eq!(map.len(), 200);
+    }
+
+    #[test]
+    fn test_hashmap_get_or_insert_with_panic() {
+        let map = NonBlockingHashMap::<usize, usize>::new_with_size(16);
+        let mut guard = pin();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            map.get_or_insert_with(1, || panic!("initialiser failed"), &mut guard);
+        }));
+        assert!(result.is_err());
+        assert!(!map.contains_key(&1));
+        assert_eq!(map.len(), 0);
+        assert_eq!(*map.get_or_insert_with(1, || 5, &mut guard), 5);
+        assert_eq!(*map.get_or_insert_with(1, || 6, &mut guard), 5);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_hashmap_pending_value_is_absent() {
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let (started_tx, started_rx) = channel();
+        let (finish_tx, finish_rx) = channel::<()>();
+        let initialiser = {
+            let map = map.clone();
+            spawn(move || {
+                let mut guard = pin();
+                *map.get_or_insert_with(
+                    0,
+                    || {
+                        started_tx.send(()).unwrap();
+                        finish_rx.recv().unwrap();
+                        42
+                    },
+                    &mut guard,
+                )
+            })
+        };
+        started_rx.recv().unwrap();
+        let guard = pin();
+        assert_eq!(map.get(&0, &guard), None);
+        assert!(!map.contains_key(&0));
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.iter(&guard).count(), 0);
+        assert_eq!(map.replace_cloned(0, 1), None);
+        // Resizes carry the pending value over to the new tables
+        for n in 1..1000 {
+            map.insert_cloned(n, n);
+        }
+        assert_eq!(map.get(&0, &guard), None);
+        finish_tx.send(()).unwrap();
+        assert_eq!(initialiser.join().expect("Error joining"), 42);
+        assert_eq!(map.get(&0, &guard), Some(&42));
+        assert_eq!(map.len(), 1000);
+    }
+
+    #[test]
+    fn test_hashmap_pending_value_overwritten() {
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        let (started_tx, started_rx) = channel();
+        let (finish_tx, finish_rx) = channel::<()>();
+        let initialiser = {
+            let map = map.clone();
+            spawn(move || {
+                let mut guard = pin();
+                *map.get_or_insert_with(
+                    0,
+                    || {
+                        started_tx.send(()).unwrap();
+                        finish_rx.recv().unwrap();
+                        42
+                    },
+                    &mut guard,
+                )
+            })
+        };
+        started_rx.recv().unwrap();
+        // A plain insert wins over the value being computed
+        assert_eq!(map.put_if_absent(0, 7, &pin()), Ok(()));
+        finish_tx.send(()).unwrap();
+        assert_eq!(initialiser.join().expect("Error joining"), 7);
+        assert_eq!(map.get_cloned(&0), Some(7));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_hashmap_get_or_insert_with_unpins() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = Arc::new(NonBlockingHashMap::<usize, Counted>::new_with_size(16));
+        map.insert(1, Counted(1, drops.clone()), &pin());
+        let (started_tx, started_rx) = channel();
+        let (finish_tx, finish_rx) = channel::<()>();
+        let initialiser = {
+            let map = map.clone();
+            let drops = drops.clone();
+            spawn(move || {
+                let mut guard = pin();
+                let f = || {
+                    started_tx.send(()).unwrap();
+                    finish_rx.recv().unwrap();
+                    Counted(0, drops)
+                };
+                map.get_or_insert_with(0, f, &mut guard).0
+            })
+        };
+        started_rx.recv().unwrap();
+        let waiter = {
+            let map = map.clone();
+            spawn(move || {
+                let mut guard = pin();
+                map.get_or_insert_with(0, || unreachable!(), &mut guard).0
+            })
+        };
+        while map._waiters.count.load(MEMORY_ORDERING) == 0 {
+            sleep(Duration::from_millis(1));
+        }
+        // Neither the thread computing the value nor the one waiting for it holds back the epoch
+        map.insert(1, Counted(2, drops.clone()), &pin());
+        wait_for_drops(&drops, 1);
+        finish_tx.send(()).unwrap();
+        assert_eq!(initialiser.join().expect("Error joining"), 0);
+        assert_eq!(waiter.join().expect("Error joining"), 0);
+        assert_eq!(map._waiters.count.load(MEMORY_ORDERING), 0);
+    }
+
+    #[test]
+    fn test_hashmap_drop_frees_table_chain() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = NonBlockingHashMap::<usize, Counted>::new_with_size(16);
+        for n in 0..1000 {
+            map.put_if_absent(n, Counted(n, drops.clone()), &pin())
+                .ok()
+                .unwrap();
+        }
+        // Leave a resize in progress
+        unsafe { map.resize(map.get_table_nonatomic()) };
+        drop(map);
+        wait_for_drops(&drops, 1000);
+    }
+
+    // Retired values are freed once no thread is pinned in the epoch they were retired in
+    fn wait_for_drops(drops: &AtomicUsize, expected: usize) {
+        for _ in 0..10_000 {
+            if drops.load(MEMORY_ORDERING) >= expected {
+                break;
+            }
+            pin().flush();
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(drops.load(MEMORY_ORDERING), expected);
+    }
+
+    #[test]
+    fn test_hashmap_iter() {
+        let map = NonBlockingHashMap::<usize, usize>::new_with_size(10);
+        let guard = pin();
+        assert_eq!(map.iter(&guard).next(), None);
+        for n in 0..1000 {
+            map.insert_cloned(n, n * 2);
+        }
+        for n in (0..1000).step_by(2) {
+            map.remove_cloned(&n);
+        }
+        let mut seen: Vec<_> = map.iter(&guard).map(|(k, v)| (*k, *v)).collect();
+        seen.sort();
+        let expected: Vec<_> = (1..1000).step_by(2).map(|n| (n, n * 2)).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_iter() {
+        let nthreads = 4;
+        let stable_keys = 1000;
+        let map = Arc::new(NonBlockingHashMap::<usize, usize>::new_with_size(16));
+        for n in 0..stable_keys {
+            map.insert_cloned(n, n);
+        }
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    // Grow the map and churn keys of its own while the main thread iterates
+                    for i in 0..20_000 {
+                        let key = stable_keys + t * 20_000 + i;
+                        map.insert_cloned(key, i);
+                        if i % 2 == 0 {
+                            map.remove_cloned(&key);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            let guard = pin();
+            let mut counts = std::collections::HashMap::new();
+            for (k, v) in map.iter(&guard) {
+                *counts.entry(*k).or_insert(0) += 1;
+                if *k < stable_keys {
+                    assert_eq!(k, v);
+                }
+            }
+            assert!(counts.values().all(|&c| c == 1));
+            assert!((0..stable_keys).all(|n| counts.contains_key(&n)));
+        }
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+    }
+
+    #[test]
+    fn test_hashmap_keys_values() {
+        let map = NonBlockingHashMap::<String, usize>::new_with_size(10);
+        for n in 0..100 {
+            map.insert_cloned(n.to_string(), n);
+        }
+        let guard = pin();
+        let mut keys: Vec<_> = map.keys(&guard).map(|k| k.parse().unwrap()).collect();
+        let mut values: Vec<_> = map.values(&guard).copied().collect();
+        keys.sort();
+        values.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<usize>>());
+        assert_eq!(values, keys);
+    }
+
+    #[test]
+    fn test_hashmap_into_iter() {
+        let map = NonBlockingHashMap::<String, String>::new_with_size(10);
+        for n in 0..1000 {
+            map.insert_cloned(n.to_string(), format!("value{}", n));
+        }
+        for n in (0..1000).step_by(3) {
+            map.remove_cloned(&n.to_string());
+        }
+        // Leave a resize in progress
+        unsafe { map.resize(map.get_table_nonatomic()) };
+        let mut entries: Vec<_> = map.into_iter().collect();
+        entries.sort_by_key(|(k, _)| k.parse::<usize>().unwrap());
+        let expected: Vec<_> = (0..1000)
+            .filter(|n| n % 3 != 0)
+            .map(|n| (n.to_string(), format!("value{}", n)))
+            .collect();
+        assert_eq!(entries, expected);
     }
 
     fn test_hashmap_concurrent(init_size: usize, nthreads: usize, num_keys: usize) {
@@ -826,9 +2975,7 @@ mod test {
                 let child_map_get = shared_map.clone();
                 let writer = spawn(move || {
                     for i in 0..num_keys {
-                        child_map_put
-                            .as_mut()
-                            .put(format!("key {}", i), format!("value {}", i));
+                        child_map_put.insert_cloned(format!("key {}", i), format!("value {}", i));
                     }
                 });
 
@@ -837,7 +2984,7 @@ mod test {
                     let mut hit = 0;
                     for i in 0..num_keys {
                         let key = format!("key {}", i);
-                        if let Some(v) = child_map_get.as_mut().get(key) {
+                        if let Some(v) = child_map_get.get(key.as_str(), &pin()) {
                             assert_eq!(*v, format!("value {}", i));
                             hit += 1;
                         }